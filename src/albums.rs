@@ -0,0 +1,254 @@
+use std::{ collections::HashMap, sync::Arc, time::Duration };
+
+use teloxide::{
+    payloads::{ SendMediaGroupSetters, SendMessageSetters },
+    requests::Requester,
+    types::{
+        ChatId,
+        InputFile,
+        InputMedia,
+        InputMediaAudio,
+        InputMediaDocument,
+        InputMediaPhoto,
+        InputMediaVideo,
+        Message,
+        MessageId,
+    },
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    config::MediaSettings,
+    database::Database,
+    matchmaking::{ self, Matchmaker },
+    messages,
+    outbound::Bot,
+    self_destruct,
+};
+
+/// How long to wait for the rest of an album after its latest item arrives.
+const ALBUM_WINDOW: Duration = Duration::from_millis(1000);
+
+struct Album {
+    partner: i64,
+    media: MediaSettings,
+    timer: Option<i32>,
+    reply_to: Option<MessageId>,
+    items: Vec<Message>,
+    /// Bumped by every item, so only the timer started by the latest one relays.
+    generation: u64,
+}
+
+/// Relays the albums sent in dialogs to the partner as single media groups.
+#[derive(Clone)]
+pub struct Albums {
+    bot: Bot,
+    db: Database,
+    matchmaker: Matchmaker,
+    pending: Arc<Mutex<HashMap<(i64, String), Album>>>,
+}
+
+impl Albums {
+    pub fn new(bot: Bot, db: Database, matchmaker: Matchmaker) -> Albums {
+        Albums {
+            bot,
+            db,
+            matchmaker,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Buffers an album item for `partner`. The group is relayed once no new
+    /// item arrived for `ALBUM_WINDOW`.
+    pub async fn push(
+        &self,
+        msg: Message,
+        partner: i64,
+        reply_to: Option<MessageId>,
+        media: MediaSettings,
+        timer: Option<i32>
+    ) {
+        let key = match msg.media_group_id() {
+            Some(group) => (msg.chat.id.0, group.to_owned()),
+            None => {
+                return;
+            }
+        };
+
+        let mut pending = self.pending.lock().await;
+        let generation = match pending.get_mut(&key) {
+            Some(album) => {
+                album.reply_to = album.reply_to.or(reply_to);
+                album.items.push(msg);
+                album.generation += 1;
+                album.generation
+            }
+            None => {
+                pending.insert(key.clone(), Album {
+                    partner,
+                    media,
+                    timer,
+                    reply_to,
+                    items: vec![msg],
+                    generation: 0,
+                });
+                0
+            }
+        };
+        drop(pending);
+
+        let albums = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(ALBUM_WINDOW).await;
+            albums.relay(key, generation).await;
+        });
+    }
+
+    async fn relay(&self, key: (i64, String), generation: u64) {
+        let album = {
+            let mut pending = self.pending.lock().await;
+            match pending.get(&key) {
+                Some(album) if album.generation == generation => pending.remove(&key).unwrap(),
+                _ => {
+                    return;
+                }
+            }
+        };
+        let sender = key.0;
+
+        // The dialog may have ended while the album was being collected.
+        match self.db.get_chat(sender).await {
+            Ok(Some(partner)) if partner == album.partner => {}
+            _ => {
+                return;
+            }
+        }
+
+        // Updates can be handled out of order; message ids keep the order the
+        // items were sent in, and with it which item carries the caption.
+        let mut items = album.items;
+        items.sort_by_key(|item| item.id.0);
+        let (items, media): (Vec<_>, Vec<_>) = items
+            .iter()
+            .filter_map(|item| input_media(item, album.media.spoiler).map(|media| (item, media)))
+            .unzip();
+
+        // Media groups need at least two items, so a lone one, e.g. the
+        // straggler of a slow album, goes out as a regular message.
+        let grouped = items.len() > 1;
+        let sent = if grouped {
+            let mut request = self.bot
+                .send_media_group(ChatId(album.partner), media)
+                .protect_content(album.media.protect_content);
+            if let Some(id) = album.reply_to {
+                request = request.reply_to_message_id(id).allow_sending_without_reply(true);
+            }
+            request.await.map(|sent| sent.iter().map(|copy| copy.id).collect())
+        } else if let Some(item) = items.first() {
+            let sent = messages::relay_media(
+                &self.bot,
+                album.partner,
+                item,
+                &album.media,
+                album.timer,
+                album.reply_to
+            ).await;
+            match sent {
+                Some(sent) => sent.map(|copy| vec![copy]),
+                None => {
+                    return;
+                }
+            }
+        } else {
+            return;
+        };
+
+        match sent {
+            Ok(copies) => {
+                for (item, copy) in items.iter().zip(&copies) {
+                    let link = self.db.add_message_link(sender, item.id.0, album.partner, copy.0);
+                    if let Err(e) = link.await {
+                        log::error!("Can't link message {}: {}", item.id.0, e);
+                    }
+                }
+
+                if let Some(seconds) = album.timer {
+                    self.self_destruct(sender, album.partner, copies, seconds, grouped).await;
+                }
+            }
+            Err(e) if matchmaking::is_unreachable(&e) => {
+                let deactivated = matchmaking::deactivate_user(
+                    &self.bot,
+                    &self.db,
+                    &self.matchmaker,
+                    album.partner
+                ).await;
+                if let Err(e) = deactivated {
+                    log::error!("{}", e);
+                }
+            }
+            Err(e) => {
+                log::error!("Can't relay album from {}: {}", sender, e);
+                let _ = self.bot
+                    .send_message(ChatId(sender), "Не получилось отправить альбом собеседнику 😔").await;
+            }
+        }
+    }
+
+    async fn self_destruct(
+        &self,
+        sender: i64,
+        partner: i64,
+        mut copies: Vec<MessageId>,
+        seconds: i32,
+        grouped: bool
+    ) {
+        let mut anchor = None;
+
+        // Albums can't carry buttons, so a view-once album gets a separate
+        // message to mark it viewed. A lone copy carries its own.
+        if seconds == self_destruct::VIEW_ONCE && grouped {
+            let button = self.bot
+                .send_message(ChatId(partner), "👆 Альбом исчезнет после просмотра")
+                .reply_markup(self_destruct::viewed_button()).await;
+
+            if let Ok(button) = button {
+                copies.push(button.id);
+                anchor = Some(button.id);
+            }
+        }
+
+        self_destruct::register(&self.bot, &self.db, sender, partner, &copies, seconds, anchor).await;
+    }
+}
+
+fn input_media(msg: &Message, spoiler: bool) -> Option<InputMedia> {
+    let caption = msg.caption().map(ToOwned::to_owned);
+    let caption_entities = msg.caption_entities().map(<[_]>::to_vec);
+
+    if let Some(photo) = msg.photo() {
+        let mut media = InputMediaPhoto::new(InputFile::file_id(&photo.last().unwrap().file.id));
+        media.has_spoiler = spoiler;
+        media.caption = caption;
+        media.caption_entities = caption_entities;
+        Some(InputMedia::Photo(media))
+    } else if let Some(video) = msg.video() {
+        let mut media = InputMediaVideo::new(InputFile::file_id(&video.file.id));
+        media.has_spoiler = spoiler;
+        media.caption = caption;
+        media.caption_entities = caption_entities;
+        Some(InputMedia::Video(media))
+    } else if let Some(document) = msg.document() {
+        let mut media = InputMediaDocument::new(InputFile::file_id(&document.file.id));
+        media.caption = caption;
+        media.caption_entities = caption_entities;
+        Some(InputMedia::Document(media))
+    } else if let Some(audio) = msg.audio() {
+        let mut media = InputMediaAudio::new(InputFile::file_id(&audio.file.id));
+        media.caption = caption;
+        media.caption_entities = caption_entities;
+        Some(InputMedia::Audio(media))
+    } else {
+        None
+    }
+}
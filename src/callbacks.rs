@@ -1,28 +1,41 @@
+use std::sync::Arc;
+
 use chrono::Datelike;
 use log::debug;
 use teloxide::{
-    payloads::SendMessageSetters,
+    payloads::{ EditMessageReplyMarkupSetters, SendMessageSetters },
     requests::Requester,
     types::{ CallbackQuery, ChatId, InlineKeyboardButton, InlineKeyboardMarkup },
-    Bot,
 };
-use tokio::sync::Mutex as TokioMutex;
 
 use crate::{
+    config::Config,
     database::Database,
-    models::{ chat_type::ChatType, gender::Gender, user::User },
+    matchmaking::{ self, Matchmaker },
+    models::{
+        chat_type::ChatType,
+        gender::Gender,
+        language,
+        search_gender::SearchGender,
+        searcher::Searcher,
+        tag::Tag,
+        user::User,
+    },
+    outbound::Bot,
+    self_destruct,
     state::State,
-    user_state::{ self, UserState },
+    user_state::UserState,
     Dialog,
     HandlerResult,
-    DATABASE,
 };
 
 pub async fn receive_gender(
     bot: Bot,
     dialog: Dialog,
     (age, nickname): (u8, String),
-    q: CallbackQuery
+    q: CallbackQuery,
+    db: Database,
+    config: Arc<Config>
 ) -> HandlerResult {
     bot.delete_message(dialog.chat_id(), q.message.unwrap().id).await?;
 
@@ -34,22 +47,24 @@ pub async fn receive_gender(
             gender = Gender::Female;
         }
 
-        let user = User::new(dialog.chat_id().0, age, nickname.clone(), gender.clone());
-        let db = DATABASE.get_or_init(||
-            TokioMutex::new(Database::new("db.db").unwrap())
-        ).lock().await;
+        let mut user = User::new(dialog.chat_id().0, age, nickname.clone(), gender.clone());
+        user.language = q.from.language_code.as_deref().and_then(language::normalize);
 
-        let _ = db.add_user(&user);
+        let _ = db.add_user(&user).await;
 
         let now = chrono::Utc::now();
 
         if now.month() == 3 || now.month() == 4 {
-            let _ = db.set_premium(dialog.chat_id().0, true);
-            let _ = db.set_premium_until(dialog.chat_id().0, now.timestamp() + 604800);
+            let _ = db.set_premium(dialog.chat_id().0, true).await;
+            let until = now + chrono::Duration::try_days(config.rules.promo_premium_days).unwrap();
+            let _ = db.set_premium_until(dialog.chat_id().0, until.timestamp()).await;
 
             bot.send_message(
                 dialog.chat_id(),
-                "💎 Вы попали на акцию, и получили бесплатный премиум на 7 дней!"
+                format!(
+                    "💎 Вы попали на акцию, и получили бесплатный премиум на {} дн.!",
+                    config.rules.promo_premium_days
+                )
             ).await?;
         }
 
@@ -62,7 +77,7 @@ pub async fn receive_gender(
             })
         ).await?;
 
-        let genders = ["Мужской ♂", "Женский ♀"].map(|product|
+        let genders = SearchGender::BUTTONS.map(|product|
             InlineKeyboardButton::callback(product, product)
         );
         bot
@@ -74,40 +89,58 @@ pub async fn receive_gender(
     Ok(())
 }
 
-pub async fn search_callback(bot: Bot, dialog: Dialog, q: CallbackQuery) -> HandlerResult {
+pub async fn search_callback(
+    bot: Bot,
+    dialog: Dialog,
+    q: CallbackQuery,
+    db: Database,
+    config: Arc<Config>,
+    matchmaker: Matchmaker
+) -> HandlerResult {
     bot.delete_message(dialog.chat_id(), q.message.clone().unwrap().id).await?;
 
     if let Some(g) = &q.data {
         if g.contains("like") {
-            reactions_callback(bot, dialog, q).await?;
+            reactions_callback(bot, dialog, q, db, config, matchmaker).await?;
             return Ok(());
         }
         if g == "cancel" {
-            let db = DATABASE.get_or_init(||
-                TokioMutex::new(Database::new("db.db").unwrap())
-            ).lock().await;
-            db.dequeue_user(dialog.chat_id().0).unwrap();
+            if
+                !matchmaker.leave(dialog.chat_id().0).await? &&
+                db.get_chat(dialog.chat_id().0).await?.is_some()
+            {
+                bot.send_message(
+                    dialog.chat_id(),
+                    "Собеседник уже найден!\n\n/stop - чтобы остановить диалог"
+                ).await?;
+                return Ok(());
+            }
             bot.send_message(dialog.chat_id(), "Поиск отменён!").await?;
             dialog.update(State::Idle).await?;
-            db.set_user_state(dialog.chat_id().0, UserState::Idle).unwrap();
+            db.set_user_state(dialog.chat_id().0, UserState::Idle).await.unwrap();
 
             return Ok(());
         }
 
-        let gender;
-
-        if g == "Мужской ♂" {
-            gender = Gender::Male;
-        } else {
-            gender = Gender::Female;
-        }
-        let cancel = [
+        let gender = match SearchGender::from_button(g) {
+            Some(gender) => gender,
+            None => {
+                return Ok(());
+            }
+        };
+        let mut chat_types = vec![vec![
             InlineKeyboardButton::callback("💬", "regular"),
             InlineKeyboardButton::callback("🔞", "vulgar"),
-        ];
+        ]];
+        if db.get_user(dialog.chat_id().0).await?.is_some_and(|user| user.region.is_some()) {
+            chat_types.push(vec![
+                InlineKeyboardButton::callback("📍 💬 Рядом", "regular_nearby"),
+                InlineKeyboardButton::callback("📍 🔞 Рядом", "vulgar_nearby"),
+            ]);
+        }
         bot
             .send_message(dialog.chat_id(), "Теперь выбери тип разговора")
-            .reply_markup(InlineKeyboardMarkup::new([cancel])).await?;
+            .reply_markup(InlineKeyboardMarkup::new(chat_types)).await?;
 
         dialog.update(State::SearchChooseChatType { gender }).await?;
     }
@@ -115,13 +148,15 @@ pub async fn search_callback(bot: Bot, dialog: Dialog, q: CallbackQuery) -> Hand
     Ok(())
 }
 
-pub async fn receive_set_gender(bot: Bot, dialog: Dialog, q: CallbackQuery) -> HandlerResult {
+pub async fn receive_set_gender(
+    bot: Bot,
+    dialog: Dialog,
+    q: CallbackQuery,
+    db: Database
+) -> HandlerResult {
     bot.delete_message(dialog.chat_id(), q.message.clone().unwrap().id).await?;
 
     if let Some(g) = &q.data {
-        let db = DATABASE.get_or_init(||
-            TokioMutex::new(Database::new("db.db").unwrap())
-        ).lock().await;
         let gender;
 
         if g == "Мужской ♂" {
@@ -129,7 +164,7 @@ pub async fn receive_set_gender(bot: Bot, dialog: Dialog, q: CallbackQuery) -> H
         } else {
             gender = Gender::Female;
         }
-        db.update_user_gender(dialog.chat_id().0, gender).unwrap();
+        db.update_user_gender(dialog.chat_id().0, gender).await.unwrap();
         bot.send_message(dialog.chat_id(), "Готово!").await?;
 
         dialog.update(State::Idle).await?;
@@ -138,7 +173,14 @@ pub async fn receive_set_gender(bot: Bot, dialog: Dialog, q: CallbackQuery) -> H
     Ok(())
 }
 
-pub async fn reactions_callback(bot: Bot, dialog: Dialog, q: CallbackQuery) -> HandlerResult {
+pub async fn reactions_callback(
+    bot: Bot,
+    dialog: Dialog,
+    q: CallbackQuery,
+    db: Database,
+    config: Arc<Config>,
+    matchmaker: Matchmaker
+) -> HandlerResult {
     if let Some(msg) = q.message {
         let _ = bot.delete_message(dialog.chat_id(), msg.id).await;
     }
@@ -146,24 +188,29 @@ pub async fn reactions_callback(bot: Bot, dialog: Dialog, q: CallbackQuery) -> H
     dialog.update(State::Idle).await?;
 
     if let Some(g) = &q.data {
-        let db = DATABASE.get_or_init(||
-            TokioMutex::new(Database::new("db.db").unwrap())
-        ).lock().await;
-
         if g.contains("dislike") {
             if let Some(id) = g.split("_").nth(1) {
-                let is_ban = db.decrease_reputation(id.parse::<i64>().unwrap(), 1).unwrap();
+                let is_ban = db
+                    .decrease_reputation(id.parse::<i64>().unwrap(), 1, config.rules.ban_reputation).await
+                    .unwrap();
 
                 if is_ban {
+                    matchmaking::remove_user(&bot, &db, &matchmaker, id.parse::<i64>().unwrap()).await?;
                     bot.send_message(
                         ChatId(id.parse::<i64>().unwrap()),
-                        "Ты был заблокирован из-за репутации ниже 20!\n\nЕсли ты уверен что это ошибка, то ты можешь написать сюда @s1nchat_admin"
+                        format!(
+                            "Ты был заблокирован из-за репутации ниже {}!\n\nЕсли ты уверен что это ошибка, то ты можешь написать сюда @{}",
+                            -config.rules.ban_reputation,
+                            config.bot.support_username
+                        )
                     ).await?;
                 }
             }
         } else {
             if let Some(id) = g.split("_").nth(1) {
-                db.increase_reputation(id.parse::<i64>().unwrap(), 1).unwrap();
+                db
+                    .increase_reputation(id.parse::<i64>().unwrap(), 1, config.rules.ban_reputation).await
+                    .unwrap();
             }
         }
     }
@@ -174,22 +221,26 @@ pub async fn chat_type_callback(
     bot: Bot,
     dialog: Dialog,
     q: CallbackQuery,
-    gender: Gender
+    gender: SearchGender,
+    db: Database,
+    matchmaker: Matchmaker
 ) -> HandlerResult {
     bot.delete_message(dialog.chat_id(), q.message.unwrap().id).await?;
 
     if let Some(g) = &q.data {
+        let (g, nearby) = match g.strip_suffix("_nearby") {
+            Some(g) => (g, true),
+            None => (g.as_str(), false),
+        };
+
         let mut chat_type = ChatType::Regular;
         if g == "regular" {
             chat_type = ChatType::Regular;
         } else if g == "vulgar" {
             chat_type = ChatType::Vulgar;
         }
-        let db = DATABASE.get_or_init(||
-            TokioMutex::new(Database::new("db.db").unwrap())
-        ).lock().await;
 
-        let user = db.get_user(dialog.chat_id().0);
+        let user = db.get_user(dialog.chat_id().0).await;
 
         if user.is_ok() {
             let user = user.unwrap();
@@ -204,100 +255,19 @@ pub async fn chat_type_callback(
                     let now = chrono::Utc::now();
 
                     if now.timestamp() > user.premium_until {
-                        db.set_premium(user.id, false).unwrap();
-                        db.set_premium_until(user.id, 0).unwrap();
+                        db.set_premium(user.id, false).await.unwrap();
+                        db.set_premium_until(user.id, 0).await.unwrap();
 
                         bot.send_message(ChatId(user.id), "Ваша подписка закончилась!").await?;
                     }
                 }
-                let result = db.enqueue_user(
-                    dialog.chat_id().0,
-                    gender,
-                    user.gender,
-                    chat_type.clone()
-                );
-
-                if result.is_ok() {
-                    let result = result.unwrap();
-                    let cancel = [InlineKeyboardButton::callback("❌ Отменить", "cancel")];
-
-                    if result != 0 {
-                        dialog.update(State::Dialog {
-                            interlocutor: result as u64,
-                        }).await?;
-                        let interlocutor = db.get_user(result).unwrap().unwrap();
-
-                        if user.is_premium {
-                            bot.send_message(
-                                dialog.chat_id(),
-                                format!(
-                                    "{} \n\n🆔: {}\nПол: {}\nПсевдоним: {} \nВозраст: {}\n\nСобеседник найден!\n\n/next - чтобы найти нового собеседника\n/stop - чтобы остановить диалог",
-                                    if chat_type == ChatType::Regular {
-                                        "💬"
-                                    } else {
-                                        "🔞"
-                                    },
-                                    interlocutor.id,
-                                    if interlocutor.gender == Gender::Male {
-                                        "Мужской ♂"
-                                    } else {
-                                        "Женский ♀"
-                                    },
-                                    interlocutor.nickname,
-                                    interlocutor.age
-                                )
-                            ).await?;
-                        } else {
-                            bot.send_message(
-                                dialog.chat_id(),
-                                format!(
-                                    "Собеседник найден!\n\n/next - чтобы найти нового собеседника\n/stop - чтобы остановить диалог"
-                                )
-                            ).await?;
-                        }
-
-                        if interlocutor.is_premium {
-                            bot.send_message(
-                                ChatId(result),
-                                format!(
-                                    "{} \n\nСобеседник найден!\n\n🆔: {}\nПол: {}\nПсевдоним: {} \nВозраст: {}\n\n/next - чтобы найти нового собеседника\n/stop - чтобы остановить диалог",
-                                    if user.chat_type == Some(ChatType::Regular) {
-                                        "💬"
-                                    } else {
-                                        "🔞"
-                                    },
-                                    user.id,
-                                    if user.gender == Gender::Male {
-                                        "Мужской ♂"
-                                    } else {
-                                        "Женский ♀"
-                                    },
-                                    user.nickname,
-                                    user.age
-                                )
-                            ).await?;
-                        } else {
-                            bot.send_message(
-                                ChatId(result),
-                                format!(
-                                    "Собеседник найден!\n\n/next - чтобы найти нового собеседника\n/stop - чтобы остановить диалог"
-                                )
-                            ).await?;
-                        }
-
-                        db.set_user_state(user.id, user_state::UserState::Dialog).unwrap();
-                        db.set_user_state(result, user_state::UserState::Dialog).unwrap();
-                    } else {
-                        bot
-                            .send_message(dialog.chat_id(), "Ищу...")
-                            .reply_markup(InlineKeyboardMarkup::new([cancel])).await?;
-                        dialog.update(State::Search).await?;
-
-                        db.set_user_state(user.id, user_state::UserState::Search).unwrap();
-                    }
-                } else {
-                    bot.send_message(dialog.chat_id(), format!("Ой! Голова кружится...")).await?;
-                }
+                matchmaking::search(
+                    &bot,
+                    &dialog,
+                    &db,
+                    &matchmaker,
+                    Searcher::new(&user, gender, chat_type, nearby && user.region.is_some())
+                ).await?;
             } else {
                 bot.send_message(dialog.chat_id(), format!("Ой! Голова кружится...")).await?;
             }
@@ -308,3 +278,157 @@ pub async fn chat_type_callback(
 
     Ok(())
 }
+
+/// Text and buttons of the /media settings message.
+pub fn media_settings_message(user: &User, config: &Config) -> (String, InlineKeyboardMarkup) {
+    let label = |value: Option<bool>| match value {
+        None => "по умолчанию",
+        Some(true) => "вкл",
+        Some(false) => "выкл",
+    };
+    let on_off = |value: bool| if value { "вкл" } else { "выкл" };
+
+    let regular = config.media_defaults(&ChatType::Regular);
+    let vulgar = config.media_defaults(&ChatType::Vulgar);
+    let text = format!(
+        "Настройки медиа\n\n🔒 Защита: твои фото и видео нельзя переслать или сохранить\n🫣 Спойлер: присланные тебе фото и видео размыты\n\nПо умолчанию:\n💬 защита {}, спойлер {}\n🔞 защита {}, спойлер {}",
+        on_off(regular.protect_content),
+        on_off(regular.spoiler),
+        on_off(vulgar.protect_content),
+        on_off(vulgar.spoiler)
+    );
+
+    let protect = format!("🔒 Защита: {}", label(user.protect_content));
+    let spoiler = format!("🫣 Спойлер: {}", label(user.media_spoiler));
+    let keyboard = InlineKeyboardMarkup::new([
+        [InlineKeyboardButton::callback(protect, "media_protect")],
+        [InlineKeyboardButton::callback(spoiler, "media_spoiler")],
+    ]);
+
+    (text, keyboard)
+}
+
+/// Cycles one of the /media overrides: default, on, off.
+pub async fn media_callback(
+    bot: Bot,
+    dialog: Dialog,
+    q: CallbackQuery,
+    db: Database,
+    config: Arc<Config>
+) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).await?;
+
+    let mut user = match db.get_user(dialog.chat_id().0).await? {
+        Some(user) => user,
+        None => {
+            return Ok(());
+        }
+    };
+
+    let next = |value: Option<bool>| match value {
+        None => Some(true),
+        Some(true) => Some(false),
+        Some(false) => None,
+    };
+    match q.data.as_deref() {
+        Some("media_protect") => {
+            user.protect_content = next(user.protect_content);
+        }
+        Some("media_spoiler") => {
+            user.media_spoiler = next(user.media_spoiler);
+        }
+        _ => {
+            return Ok(());
+        }
+    }
+    db.update_media_settings(user.id, user.protect_content, user.media_spoiler).await?;
+
+    if let Some(msg) = q.message {
+        let (_, keyboard) = media_settings_message(&user, &config);
+        bot.edit_message_reply_markup(dialog.chat_id(), msg.id).reply_markup(keyboard).await?;
+    }
+
+    Ok(())
+}
+
+/// Buttons of the /interests message, two tags a row; picked ones are ticked.
+pub fn interests_keyboard(catalogue: &[Tag], selected: &[Tag]) -> InlineKeyboardMarkup {
+    let mut rows: Vec<Vec<InlineKeyboardButton>> = catalogue
+        .chunks(2)
+        .map(|row| {
+            row.iter()
+                .map(|tag| {
+                    let label = if selected.contains(tag) {
+                        format!("✅ {}", tag.name)
+                    } else {
+                        tag.name.clone()
+                    };
+                    InlineKeyboardButton::callback(label, format!("tag_{}", tag.id))
+                })
+                .collect()
+        })
+        .collect();
+    rows.push(vec![InlineKeyboardButton::callback("Готово", "tag_done")]);
+
+    InlineKeyboardMarkup::new(rows)
+}
+
+/// Picks or drops one of the user's interest tags, or closes the keyboard.
+pub async fn interests_callback(bot: Bot, dialog: Dialog, q: CallbackQuery, db: Database) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).await?;
+
+    let msg = match q.message {
+        Some(msg) => msg,
+        None => {
+            return Ok(());
+        }
+    };
+    let user_id = dialog.chat_id().0;
+    let selected = db.get_user_tags(user_id).await?;
+
+    match q.data.as_deref().and_then(|data| data.strip_prefix("tag_")) {
+        Some("done") => {
+            let text = if selected.is_empty() {
+                "Интересы не выбраны".to_owned()
+            } else {
+                let names: Vec<&str> = selected
+                    .iter()
+                    .map(|tag| tag.name.as_str())
+                    .collect();
+                format!("Твои интересы: {}", names.join(", "))
+            };
+            bot.edit_message_text(dialog.chat_id(), msg.id, text).await?;
+        }
+        Some(id) => {
+            let tag_id = match id.parse::<i32>() {
+                Ok(tag_id) => tag_id,
+                Err(_) => {
+                    return Ok(());
+                }
+            };
+            let picked = selected.iter().any(|tag| tag.id == tag_id);
+            db.set_user_tag(user_id, tag_id, !picked).await?;
+
+            let catalogue = db.get_tags().await?;
+            let selected = db.get_user_tags(user_id).await?;
+            bot
+                .edit_message_reply_markup(dialog.chat_id(), msg.id)
+                .reply_markup(interests_keyboard(&catalogue, &selected)).await?;
+        }
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Deletes a view-once copy, and the rest of its album, once it was seen.
+pub async fn viewed_callback(bot: Bot, dialog: Dialog, q: CallbackQuery, db: Database) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).await?;
+
+    if let Some(msg) = q.message {
+        self_destruct::viewed(&bot, &db, dialog.chat_id().0, msg.id).await?;
+        let _ = bot.delete_message(dialog.chat_id(), msg.id).await;
+    }
+
+    Ok(())
+}
@@ -8,6 +8,13 @@ pub enum Command {
 
     #[command(description = "Админ команда чтобы отправить сообщение всем пользователям")]
     Message,
+
+    #[command(description = "Админ команда чтобы исправить состояния пользователей, очередь и чаты")]
+    Reconcile,
+
+    #[command(description = "Админ команда чтобы управлять интересами: /tags add <имя>, /tags remove <имя>")]
+    Tags,
+
     #[command(description = "Забанить пользователя")]
     Ban,
 
@@ -35,6 +42,9 @@ pub enum Command {
     #[command(description = "Остановить диалог")]
     Stop,
 
+    #[command(description = "Удалить своё сообщение у собеседника (ответом или последнее)")]
+    Unsend,
+
     #[command(description = "Изменить имя")]
     SetName,
 
@@ -44,6 +54,24 @@ pub enum Command {
     #[command(description = "Изменить пол")]
     SetGender,
 
+    #[command(description = "Изменить язык: /setlanguage en, /setlanguage only, /setlanguage any")]
+    SetLanguage,
+
+    #[command(description = "Поделиться местоположением для поиска рядом, /setlocation off - удалить")]
+    SetLocation,
+
+    #[command(description = "Возраст собеседника: /partnerage 18 25, /partnerage off")]
+    PartnerAge,
+
+    #[command(description = "Выбрать интересы")]
+    Interests,
+
+    #[command(description = "Настройки медиа: защита от пересылки и спойлер")]
+    Media,
+
+    #[command(description = "Самоуничтожение фото и видео: /timer 10, /timer once, /timer off")]
+    Timer,
+
     #[command(description = "Правила")]
     Rules,
 
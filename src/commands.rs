@@ -1,53 +1,84 @@
-use std::{ env, time::Duration };
+use std::{ collections::HashSet, sync::Arc, time::Duration };
 
 use chrono::{ DateTime, Datelike };
 use teloxide::{
     payloads::SendMessageSetters,
     requests::Requester,
-    types::{ ChatId, InlineKeyboardButton, InlineKeyboardMarkup, Message },
-    Bot,
+    types::{ ChatId, InlineKeyboardButton, InlineKeyboardMarkup, Message, MessageId },
+    ApiError,
+    RequestError,
 };
-use tokio::sync::Mutex as TokioMutex;
 
 use crate::{
+    albums::Albums,
+    callbacks,
+    config::Config,
     database::Database,
+    matchmaking::{ self, Matchmaker },
     messages::receive_message,
-    models::{ chat_type::ChatType, gender::Gender },
+    models::{
+        chat_type::ChatType,
+        gender::Gender,
+        language,
+        search_gender::SearchGender,
+        searcher::Searcher,
+    },
+    outbound::{ self, Bot },
+    reconciliation,
+    self_destruct,
     state::State,
-    user_state::{ self, UserState },
+    user_state::UserState,
     Dialog,
     HandlerResult,
-    DATABASE,
 };
 
-pub async fn admin_message(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
-    let admin = env::var("ADMIN").unwrap();
-    if msg.chat.id.0.to_string() == admin {
+pub async fn admin_message(
+    bot: Bot,
+    _: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>
+) -> HandlerResult {
+    if !config.is_admin(msg.chat.id.0) {
+        return Ok(());
     }
 
-    let db = DATABASE.get_or_init(|| TokioMutex::new(Database::new("db.db").unwrap()));
-    let users = db.lock().await.get_all_users().unwrap();
+    let users = db.get_all_users().await.unwrap();
 
-    for user in users {
-        let _ = bot.send_message(
+    // Paced so relays in running dialogs keep most of the rate limit.
+    let mut pace = outbound::bulk_pace();
+    for user in users.into_iter().filter(|user| user.is_active) {
+        pace.tick().await;
+        let sent = bot.send_message(
             ChatId(user.id),
             format!("--- SinChat ---\n\n{}", msg.text().unwrap().split("/message").nth(1).unwrap())
         ).await;
+        matchmaking::check_reachable(&db, user.id, sent).await;
     }
 
     Ok(())
 }
 
-pub async fn rules(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
+pub async fn rules(bot: Bot, _: Dialog, msg: Message, config: Arc<Config>) -> HandlerResult {
     bot.send_message(
         msg.chat.id,
-        "Что ЗАПРЕЩЕННО в SinChat\n\n💬Общие\nРеклама\nПопрошайничество\nСпам\nНацизм / фашизм / расизм\nБулинг\n\n💬 Обычный чат\nРазговор на темы 18+ \nВыпрашивание интимных фотографий\n\n🔞 Пошлый чат\nОбщаться на НЕ пошлые темы\nИскать друзей\n\nЗа любое нарушение правил ваша репутация снижается, если ваша репутация иже 20, вы будете заблокированы.\n\n⚠️НЕ ЗНАНИЕ ПРАВИЛ, НЕ УБИРАЕТ С ВАС ОТВЕТСВЕННОСТИ⚠️"
+        format!(
+            "Что ЗАПРЕЩЕННО в SinChat\n\n💬Общие\nРеклама\nПопрошайничество\nСпам\nНацизм / фашизм / расизм\nБулинг\n\n💬 Обычный чат\nРазговор на темы 18+ \nВыпрашивание интимных фотографий\n\n🔞 Пошлый чат\nОбщаться на НЕ пошлые темы\nИскать друзей\n\nЗа любое нарушение правил ваша репутация снижается, если ваша репутация иже {}, вы будете заблокированы.\n\n⚠️НЕ ЗНАНИЕ ПРАВИЛ, НЕ УБИРАЕТ С ВАС ОТВЕТСВЕННОСТИ⚠️",
+            -config.rules.ban_reputation
+        )
     ).await?;
 
     Ok(())
 }
 
-pub async fn ban(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
+pub async fn ban(
+    bot: Bot,
+    _: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>,
+    matchmaker: Matchmaker
+) -> HandlerResult {
     if let Some(txt) = msg.text() {
         if txt.split("/ban").nth(1).is_none() {
             bot.send_message(msg.chat.id, format!("Что-то не так")).await?;
@@ -55,13 +86,10 @@ pub async fn ban(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
         }
     }
 
-    let admin = env::var("ADMIN").unwrap();
-
-    if msg.chat.id.0.to_string() == admin {
-        let db = Database::new("db.db").unwrap();
+    if config.is_admin(msg.chat.id.0) {
         let user = db.get_user(
             msg.text().unwrap().split("/ban").nth(1).unwrap().trim().parse::<i64>().unwrap()
-        );
+        ).await;
 
         if user.is_ok() {
             let user = user.unwrap();
@@ -79,7 +107,8 @@ pub async fn ban(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
                     .parse::<i64>()
                     .unwrap_or(0);
                 if id != 0 {
-                    db.ban_user(id).unwrap();
+                    db.ban_user(id).await.unwrap();
+                    matchmaking::remove_user(&bot, &db, &matchmaker, id).await?;
                     bot.send_message(msg.chat.id, format!("Готово\n\n{:#?}", user)).await?;
                 } else {
                     bot.send_message(msg.chat.id, format!("Что-то не так")).await?;
@@ -91,7 +120,13 @@ pub async fn ban(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
     Ok(())
 }
 
-pub async fn unban(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
+pub async fn unban(
+    bot: Bot,
+    _: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>
+) -> HandlerResult {
     if let Some(txt) = msg.text() {
         if txt.split("/unban").nth(1).is_none() {
             bot.send_message(msg.chat.id, format!("Что-то не так")).await?;
@@ -99,13 +134,10 @@ pub async fn unban(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
         }
     }
 
-    let admin = env::var("ADMIN").unwrap();
-
-    if msg.chat.id.0.to_string() == admin {
-        let db = Database::new("db.db").unwrap();
+    if config.is_admin(msg.chat.id.0) {
         let user = db.get_user(
             msg.text().unwrap().split("/unban").nth(1).unwrap().trim().parse::<i64>().unwrap()
-        );
+        ).await;
 
         if user.is_ok() {
             let user = user.unwrap();
@@ -123,7 +155,7 @@ pub async fn unban(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
                     .parse::<i64>()
                     .unwrap_or(0);
                 if id != 0 {
-                    db.unban_user(id).unwrap();
+                    db.unban_user(id).await.unwrap();
                     bot.send_message(msg.chat.id, format!("Готово\n\n{:#?}", user)).await?;
                 } else {
                     bot.send_message(msg.chat.id, format!("Что-то не так")).await?;
@@ -135,11 +167,16 @@ pub async fn unban(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
     Ok(())
 }
 
-pub async fn referral(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
-    let link = format!("https://t.me/s1nchat_bot?start={}", msg.chat.id.0);
+pub async fn referral(bot: Bot, _: Dialog, msg: Message, config: Arc<Config>) -> HandlerResult {
+    let link = config.referral_link(msg.chat.id.0);
     bot.send_message(
         msg.chat.id,
-        format!("Пригласи 10 человек и получи бесплатный премиум на неделю!\n\nТвоя реферальная ссылка: {}\n\nПример использования:", link)
+        format!(
+            "Пригласи {} человек и получи бесплатный премиум на {} дн.!\n\nТвоя реферальная ссылка: {}\n\nПример использования:",
+            config.rules.referral_premium_threshold,
+            config.rules.referral_premium_days,
+            link
+        )
     ).await?;
 
     bot.send_message(
@@ -150,9 +187,8 @@ pub async fn referral(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
     Ok(())
 }
 
-pub async fn top(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
-    let db = DATABASE.get().unwrap().lock().await;
-    let users = db.get_top_referral_users(10);
+pub async fn top(bot: Bot, _: Dialog, msg: Message, db: Database) -> HandlerResult {
+    let users = db.get_top_referral_users(10).await;
     if users.is_ok() {
         let users = users.unwrap();
         let mut response = String::new();
@@ -181,17 +217,273 @@ pub async fn top(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
     Ok(())
 }
 
-pub async fn premium(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
+pub async fn media(
+    bot: Bot,
+    _: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>
+) -> HandlerResult {
+    match db.get_user(msg.chat.id.0).await? {
+        Some(user) => {
+            let (text, keyboard) = callbacks::media_settings_message(&user, &config);
+            bot.send_message(msg.chat.id, text).reply_markup(keyboard).await?;
+        }
+        None => {
+            bot.send_message(msg.chat.id, "Ты не зарегистрирован!\n\n/start").await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn interests(bot: Bot, _: Dialog, msg: Message, db: Database) -> HandlerResult {
+    let catalogue = db.get_tags().await?;
+    if catalogue.is_empty() {
+        bot.send_message(msg.chat.id, "Список интересов пока пуст").await?;
+        return Ok(());
+    }
+
+    let selected = db.get_user_tags(msg.chat.id.0).await?;
+    bot
+        .send_message(
+            msg.chat.id,
+            "🏷 Выбери свои интересы, собеседники с общими интересами найдутся в первую очередь"
+        )
+        .reply_markup(callbacks::interests_keyboard(&catalogue, &selected)).await?;
+
+    Ok(())
+}
+
+/// Sets the user's language and whether partners must speak it.
+pub async fn set_language(bot: Bot, _: Dialog, msg: Message, db: Database) -> HandlerResult {
+    let user = match db.get_user(msg.chat.id.0).await? {
+        Some(user) => user,
+        None => {
+            bot.send_message(msg.chat.id, "Ты не зарегистрирован!\n\n/start").await?;
+            return Ok(());
+        }
+    };
+
+    let (language, strict) = match msg.text().and_then(|txt| txt.split_whitespace().nth(1)) {
+        Some("only") => (user.language.clone(), true),
+        Some("any") => (user.language.clone(), false),
+        Some(code) =>
+            match language::normalize(code) {
+                Some(language) => (Some(language), user.strict_language),
+                None => {
+                    bot.send_message(msg.chat.id, "Укажи код языка, например ru или en").await?;
+                    return Ok(());
+                }
+            }
+        None => {
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "🌐 Язык\n\n/setlanguage en - указать свой язык\n/setlanguage only - искать только собеседников на моём языке\n/setlanguage any - предпочитать свой язык, но искать всех\n\nСейчас: {}",
+                    language_label(user.language.as_deref(), user.strict_language)
+                )
+            ).await?;
+            return Ok(());
+        }
+    };
+
+    if strict && language.is_none() {
+        bot.send_message(msg.chat.id, "Сначала укажи свой язык, например /setlanguage ru").await?;
+        return Ok(());
+    }
+
+    db.update_language(user.id, language.as_deref(), strict).await?;
+    bot.send_message(
+        msg.chat.id,
+        format!("Готово! Язык: {}", language_label(language.as_deref(), strict))
+    ).await?;
+
+    Ok(())
+}
+
+fn language_label(language: Option<&str>, strict: bool) -> String {
+    match (language, strict) {
+        (None, _) => "не указан".to_owned(),
+        (Some(language), false) => format!("{}, собеседники на нём в приоритете", language),
+        (Some(language), true) => format!("{}, только собеседники на нём", language),
+    }
+}
+
+/// Longest interest tag name, so two still fit on a keyboard row.
+const MAX_TAG_LENGTH: usize = 24;
+
+/// Lists, adds and removes the interest tags users can pick.
+pub async fn tags(
+    bot: Bot,
+    _: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>
+) -> HandlerResult {
+    if !config.is_admin(msg.chat.id.0) {
+        return Ok(());
+    }
+
+    let mut args = msg.text().unwrap_or_default().splitn(3, char::is_whitespace).skip(1);
+    let action = args.next();
+    let name = args.next().unwrap_or_default().trim();
+
+    let reply = match action {
+        Some("add") if !name.is_empty() => {
+            if name.chars().count() > MAX_TAG_LENGTH {
+                format!("Слишком длинное имя, максимум {} символов", MAX_TAG_LENGTH)
+            } else if db.add_tag(name).await? {
+                "Готово".to_owned()
+            } else {
+                "Такой интерес уже есть".to_owned()
+            }
+        }
+        Some("remove") if !name.is_empty() => {
+            if db.remove_tag(name).await? {
+                "Готово".to_owned()
+            } else {
+                "Такого интереса нет".to_owned()
+            }
+        }
+        _ => {
+            let names: Vec<String> = db
+                .get_tags().await?
+                .into_iter()
+                .map(|tag| tag.name)
+                .collect();
+            format!(
+                "Интересы: {}\n\n/tags add <имя> - добавить\n/tags remove <имя> - удалить",
+                if names.is_empty() {
+                    "нет".to_owned()
+                } else {
+                    names.join(", ")
+                }
+            )
+        }
+    };
+    bot.send_message(msg.chat.id, reply).await?;
+
+    Ok(())
+}
+
+/// Sets how long the user's photos and videos live in the partner's chat.
+pub async fn timer(bot: Bot, _: Dialog, msg: Message, db: Database) -> HandlerResult {
+    let seconds = match msg.text().and_then(|txt| txt.split_whitespace().nth(1)) {
+        Some("off") => None,
+        Some("once") => Some(self_destruct::VIEW_ONCE),
+        Some(value) =>
+            match value.parse::<i32>() {
+                Ok(seconds) if (1..=self_destruct::MAX_SECONDS).contains(&seconds) => Some(seconds),
+                _ => {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!("Укажи время от 1 до {} секунд", self_destruct::MAX_SECONDS)
+                    ).await?;
+                    return Ok(());
+                }
+            }
+        None => {
+            let current = db
+                .get_user(msg.chat.id.0).await?
+                .and_then(|user| user.self_destruct);
+
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "⏱ Самоуничтожение фото и видео\n\n/timer 10 - удалить у собеседника через 10 секунд\n/timer once - удалить после просмотра\n/timer off - выключить\n\nСейчас: {}",
+                    timer_label(current)
+                )
+            ).await?;
+            return Ok(());
+        }
+    };
+
+    db.update_self_destruct(msg.chat.id.0, seconds).await?;
+    bot.send_message(msg.chat.id, format!("Готово! Самоуничтожение: {}", timer_label(seconds))).await?;
+
+    Ok(())
+}
+
+fn timer_label(seconds: Option<i32>) -> String {
+    match seconds {
+        None => "выключено".to_owned(),
+        Some(self_destruct::VIEW_ONCE) => "после просмотра".to_owned(),
+        Some(seconds) => format!("через {} сек.", seconds),
+    }
+}
+
+pub async fn partner_age(
+    bot: Bot,
+    _: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>
+) -> HandlerResult {
+    let args: Vec<&str> = msg.text().unwrap_or_default().split_whitespace().skip(1).collect();
+
+    let range = match args[..] {
+        ["off"] => None,
+        [min, max] =>
+            match (min.parse::<u8>(), max.parse::<u8>()) {
+                (Ok(min), Ok(max)) if min >= config.rules.min_age && min <= max => Some((min, max)),
+                _ => {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!(
+                            "Укажи два возраста, от меньшего к большему, не младше {}",
+                            config.rules.min_age
+                        )
+                    ).await?;
+                    return Ok(());
+                }
+            }
+        _ => {
+            let current = db
+                .get_user(msg.chat.id.0).await?
+                .and_then(|user| user.partner_age);
+
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "🎂 Возраст собеседника\n\n/partnerage 18 25 - искать собеседников от 18 до 25 лет\n/partnerage off - любой возраст\n\nСейчас: {}",
+                    partner_age_label(current)
+                )
+            ).await?;
+            return Ok(());
+        }
+    };
+
+    db.update_partner_age(msg.chat.id.0, range).await?;
+    bot.send_message(
+        msg.chat.id,
+        format!("Готово! Возраст собеседника: {}", partner_age_label(range))
+    ).await?;
+
+    Ok(())
+}
+
+fn partner_age_label(range: Option<(u8, u8)>) -> String {
+    match range {
+        None => "любой".to_owned(),
+        Some((min, max)) => format!("от {} до {}", min, max),
+    }
+}
+
+pub async fn premium(bot: Bot, _: Dialog, msg: Message, config: Arc<Config>) -> HandlerResult {
     bot.send_message(
         msg.chat.id,
-        "Пригласи 10 человек и получи бесплатный премиум на неделю!\n\n💎 Что даёт премиум?\n\nПолучив премиум вы можете:\n\n1. Иметь полную информацию о собеседнике\n2. разделение пошлого и обычного чата\n3. Первее получите доступ к новым функциям чата\n4. Все видят ваш премиум"
+        format!(
+            "Пригласи {} человек и получи бесплатный премиум на {} дн.!\n\n💎 Что даёт премиум?\n\nПолучив премиум вы можете:\n\n1. Иметь полную информацию о собеседнике\n2. разделение пошлого и обычного чата\n3. Первее получите доступ к новым функциям чата\n4. Все видят ваш премиум",
+            config.rules.referral_premium_threshold,
+            config.rules.referral_premium_days
+        )
     ).await.unwrap();
 
     Ok(())
 }
-pub async fn top_rep(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
-    let db = DATABASE.get().unwrap().lock().await;
-    let users = db.get_top_reputation_users(10);
+pub async fn top_rep(bot: Bot, _: Dialog, msg: Message, db: Database) -> HandlerResult {
+    let users = db.get_top_reputation_users(10).await;
     if users.is_ok() {
         let users = users.unwrap();
         let mut response = String::new();
@@ -224,11 +516,14 @@ pub async fn top_rep(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
     Ok(())
 }
 
-pub async fn user_info(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
-    let admin = env::var("ADMIN").unwrap();
-
-    if msg.chat.id.0.to_string() == admin {
-        let db = Database::new("db.db").unwrap();
+pub async fn user_info(
+    bot: Bot,
+    _: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>
+) -> HandlerResult {
+    if config.is_admin(msg.chat.id.0) {
         let user = db.get_user(
             msg
                 .text()
@@ -239,7 +534,7 @@ pub async fn user_info(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
                 .trim()
                 .parse::<i64>()
                 .unwrap_or(msg.chat.id.0)
-        );
+        ).await;
 
         if user.is_ok() {
             let user = user.unwrap();
@@ -272,13 +567,12 @@ pub async fn user_info(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
             }
         }
     } else {
-        let db = Database::new("db.db").unwrap();
-        let user = db.get_user(msg.chat.id.0).unwrap().unwrap();
+        let user = db.get_user(msg.chat.id.0).await.unwrap().unwrap();
 
         bot.send_message(
             msg.chat.id,
             format!(
-                "{}\n\nНикнейм: {}\nПол: {}\nВозраст: {}\nРепутация: {}\nКоличество приглашенных людей: {}",
+                "{}\n\nНикнейм: {}\nПол: {}\nВозраст: {}\nЯзык: {}\nРепутация: {}\nКоличество приглашенных людей: {}",
                 user.id,
                 user.nickname,
                 if user.gender == Gender::Male {
@@ -287,6 +581,7 @@ pub async fn user_info(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
                     "Женский ♀"
                 },
                 user.age,
+                language_label(user.language.as_deref(), user.strict_language),
                 user.reputation,
                 user.referrals
             )
@@ -296,7 +591,14 @@ pub async fn user_info(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
     Ok(())
 }
 
-pub async fn delete_user(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
+pub async fn delete_user(
+    bot: Bot,
+    _: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>,
+    matchmaker: Matchmaker
+) -> HandlerResult {
     if let Some(txt) = msg.text() {
         if txt.split("/delete").nth(1).is_none() {
             bot.send_message(msg.chat.id, format!("Что-то не так")).await?;
@@ -304,13 +606,10 @@ pub async fn delete_user(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
         }
     }
 
-    let admin = env::var("ADMIN").unwrap();
-
-    if msg.chat.id.0.to_string() == admin {
-        let db = Database::new("db.db").unwrap();
+    if config.is_admin(msg.chat.id.0) {
         let user = db.get_user(
             msg.text().unwrap().split("/delete").nth(1).unwrap().trim().parse::<i64>().unwrap()
-        );
+        ).await;
 
         if user.is_ok() {
             let user = user.unwrap();
@@ -328,7 +627,8 @@ pub async fn delete_user(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
                     .parse::<i64>()
                     .unwrap_or(0);
                 if id != 0 {
-                    db.delete_user_by_id(id).unwrap();
+                    matchmaking::remove_user(&bot, &db, &matchmaker, id).await?;
+                    db.delete_user_by_id(id).await.unwrap();
                     bot.send_message(msg.chat.id, format!("Готово\n\n{:#?}", user)).await?;
                 } else {
                     bot.send_message(msg.chat.id, format!("Что-то не так")).await?;
@@ -339,29 +639,80 @@ pub async fn delete_user(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
 
     Ok(())
 }
-pub async fn admin(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
-    let admin = env::var("ADMIN").unwrap();
-    let db = Database::new("db.db").unwrap();
-    let total_users = db.get_total_users()?;
-    let female_count = db.get_female_count()?;
-    let male_count = db.get_male_count()?;
-    let total_chats = db.get_total_chats()?;
-    let total_queue = db.get_queue_count()?;
-    let total_male_queue = db.get_male_queue_count()?;
-    let total_female_queue = db.get_female_queue_count()?;
-
-    if msg.chat.id.0.to_string() == admin {
+
+const WAIT_STATS_PERIOD: i64 = 7 * 24 * 60 * 60;
+
+/// One line per gender and chat type with the average and 95th percentile wait.
+fn wait_stats(waits: &[(Gender, ChatType, i64)]) -> String {
+    let mut lines = Vec::new();
+
+    for gender in [Gender::Male, Gender::Female] {
+        for chat_type in [ChatType::Regular, ChatType::Vulgar] {
+            let mut waited: Vec<i64> = waits
+                .iter()
+                .filter(|(g, c, _)| *g == gender && *c == chat_type)
+                .map(|(_, _, waited)| *waited)
+                .collect();
+
+            let label = format!(
+                "{} {}",
+                if gender == Gender::Male {
+                    "Мужской ♂"
+                } else {
+                    "Женский ♀"
+                },
+                if chat_type == ChatType::Regular {
+                    "💬"
+                } else {
+                    "🔞"
+                }
+            );
+
+            if waited.is_empty() {
+                lines.push(format!("{}: -", label));
+                continue;
+            }
+
+            waited.sort_unstable();
+            let avg = waited.iter().sum::<i64>() / (waited.len() as i64);
+            let p95 = waited[(waited.len() * 95).div_ceil(100) - 1];
+
+            lines.push(format!("{}: {}s / {}s ({})", label, avg, p95, waited.len()));
+        }
+    }
+
+    lines.join("\n")
+}
+
+pub async fn admin(
+    bot: Bot,
+    _: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>
+) -> HandlerResult {
+    let total_users = db.get_total_users().await?;
+    let female_count = db.get_female_count().await?;
+    let male_count = db.get_male_count().await?;
+    let total_chats = db.get_total_chats().await?;
+    let total_queue = db.get_queue_count().await?;
+    let total_male_queue = db.get_male_queue_count().await?;
+    let total_female_queue = db.get_female_queue_count().await?;
+    let waits = db.get_wait_times(chrono::Utc::now().timestamp() - WAIT_STATS_PERIOD).await?;
+
+    if config.is_admin(msg.chat.id.0) {
         bot.send_message(
             msg.chat.id,
             format!(
-                "Users: {}\nМужской ♂ Males: {}\nЖенский ♀ Females: {}\n\n💬 Chats: {}\nQueue: {}\n\n\nМужской ♂ Queue Males: {}\nЖенский ♀ Queue Females: {}",
+                "Users: {}\nМужской ♂ Males: {}\nЖенский ♀ Females: {}\n\n💬 Chats: {}\nQueue: {}\n\n\nМужской ♂ Queue Males: {}\nЖенский ♀ Queue Females: {}\n\n⏱ Wait (7d), avg / p95:\n{}",
                 total_users,
                 male_count,
                 female_count,
                 total_chats,
                 total_queue,
                 total_male_queue,
-                total_female_queue
+                total_female_queue,
+                wait_stats(&waits)
             )
         ).await?;
     }
@@ -369,10 +720,30 @@ pub async fn admin(bot: Bot, _: Dialog, msg: Message) -> HandlerResult {
     Ok(())
 }
 
-pub async fn stop(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
-    let db = DATABASE.get_or_init(|| TokioMutex::new(Database::new("db.db").unwrap())).lock().await;
+pub async fn reconcile(
+    bot: Bot,
+    _: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>,
+    matchmaker: Matchmaker
+) -> HandlerResult {
+    if config.is_admin(msg.chat.id.0) {
+        // No pairing while chats and the queue are being rewritten.
+        let paused = matchmaker.pause().await?;
+        let report = reconciliation::reconcile(&db).await?;
+        drop(paused);
+
+        reconciliation::notify_users(&bot, &report, &HashSet::new()).await;
+
+        bot.send_message(msg.chat.id, report.summary()).await?;
+    }
+
+    Ok(())
+}
 
-    let intr = db.delete_chat(dialog.chat_id().0);
+pub async fn stop(bot: Bot, dialog: Dialog, msg: Message, db: Database) -> HandlerResult {
+    let intr = db.delete_chat(dialog.chat_id().0).await;
     dialog.update(State::Idle).await?;
 
     if intr.is_ok() {
@@ -381,8 +752,9 @@ pub async fn stop(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
         if intr.is_some() {
             let intr = intr.unwrap();
 
-            db.set_user_state(msg.chat.id.0, UserState::Idle).unwrap();
-            db.set_user_state(intr, UserState::Idle).unwrap();
+            db.set_user_state(msg.chat.id.0, UserState::Idle).await.unwrap();
+            db.set_user_state(intr, UserState::Idle).await.unwrap();
+            self_destruct::clear_dialog(&bot, &db, msg.chat.id.0, intr).await;
 
             let reactions = [
                 InlineKeyboardButton::callback("👍", format!("like_{}", intr)),
@@ -399,9 +771,10 @@ pub async fn stop(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
                 InlineKeyboardButton::callback("👍", format!("like_{}", msg.chat.id)),
                 InlineKeyboardButton::callback("👎", format!("dislike_{}", msg.chat.id)),
             ];
-            bot
+            let sent = bot
                 .send_message(ChatId(intr), "Твой собеседник остановил диалог!!")
-                .reply_markup(InlineKeyboardMarkup::new([reactions])).await?;
+                .reply_markup(InlineKeyboardMarkup::new([reactions])).await;
+            matchmaking::check_reachable(&db, intr, sent).await;
         } else {
             bot.send_message(msg.chat.id, "Ты не находишься в диалоге!").await?;
         }
@@ -413,19 +786,77 @@ pub async fn stop(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
     Ok(())
 }
 
-pub async fn cancel(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
-    let db = DATABASE.get_or_init(|| TokioMutex::new(Database::new("db.db").unwrap())).lock().await;
-    db.dequeue_user(msg.chat.id.0).unwrap();
+/// Deletes the partner's copy of the replied-to message, or of the last one
+/// the user sent if the command isn't a reply.
+pub async fn unsend(bot: Bot, _: Dialog, msg: Message, db: Database) -> HandlerResult {
+    let partner = db.get_chat(msg.chat.id.0).await?;
+
+    let target = match msg.reply_to_message() {
+        Some(rpmsg) => db
+            .get_relayed_copy(msg.chat.id.0, rpmsg.id.0).await?
+            .map(|(chat, id)| (rpmsg.id.0, chat, id)),
+        None => db.get_last_relayed_message(msg.chat.id.0).await?,
+    };
+
+    let (sender_msg, chat, copy) = match target {
+        Some(target) if Some(target.1) == partner => target,
+        _ => {
+            bot.send_message(msg.chat.id, "Не нашёл это сообщение у собеседника 🤷").await?;
+            return Ok(());
+        }
+    };
+
+    match bot.delete_message(ChatId(chat), MessageId(copy)).await {
+        Ok(_) => {
+            db.delete_message_link(msg.chat.id.0, sender_msg).await?;
+            bot.send_message(msg.chat.id, "Сообщение удалено у собеседника 🗑").await?;
+        }
+        Err(RequestError::Api(ApiError::MessageToDeleteNotFound)) => {
+            db.delete_message_link(msg.chat.id.0, sender_msg).await?;
+            bot.send_message(msg.chat.id, "Собеседник уже удалил это сообщение").await?;
+        }
+        Err(e) => {
+            log::warn!("Can't unsend message {} in {}: {}", copy, chat, e);
+            bot.send_message(
+                msg.chat.id,
+                "Не удалось удалить сообщение 😔\n\nTelegram позволяет удалять только сообщения младше 48 часов"
+            ).await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn cancel(
+    bot: Bot,
+    dialog: Dialog,
+    msg: Message,
+    db: Database,
+    matchmaker: Matchmaker
+) -> HandlerResult {
+    // Leaving fails only if the match won the race; keep that dialog.
+    if !matchmaker.leave(msg.chat.id.0).await? && db.get_chat(msg.chat.id.0).await?.is_some() {
+        bot.send_message(
+            msg.chat.id,
+            "Собеседник уже найден!\n\n/stop - чтобы остановить диалог"
+        ).await?;
+        return Ok(());
+    }
     bot.send_message(msg.chat.id, "Поиск отменён!").await?;
     dialog.update(State::Idle).await?;
-    db.set_user_state(msg.chat.id.0, UserState::Idle).unwrap();
+    db.set_user_state(msg.chat.id.0, UserState::Idle).await.unwrap();
 
     Ok(())
 }
 
-pub async fn next(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
-    let db = DATABASE.get().unwrap().lock().await;
-    let user = db.get_user(msg.chat.id.0);
+pub async fn next(
+    bot: Bot,
+    dialog: Dialog,
+    msg: Message,
+    db: Database,
+    matchmaker: Matchmaker
+) -> HandlerResult {
+    let user = db.get_user(msg.chat.id.0).await;
 
     if user.is_ok() {
         let user = user.unwrap();
@@ -440,8 +871,8 @@ pub async fn next(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
                 let now = chrono::Utc::now();
 
                 if now.timestamp() > user.premium_until {
-                    db.set_premium(user.id, false).unwrap();
-                    db.set_premium_until(user.id, 0).unwrap();
+                    db.set_premium(user.id, false).await.unwrap();
+                    db.set_premium_until(user.id, 0).await.unwrap();
 
                     bot.send_message(ChatId(user.id), "Ваша подписка закончилась!").await?;
                 }
@@ -455,14 +886,15 @@ pub async fn next(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
                 return Ok(());
             }
 
-            let chat = db.get_chat(msg.chat.id.0);
+            let chat = db.get_chat(msg.chat.id.0).await;
             if chat.is_ok() {
                 let chat = chat.unwrap();
                 if chat.is_some() {
                     let chat = chat.unwrap();
-                    let _ = db.delete_chat(msg.chat.id.0);
-                    db.set_user_state(msg.chat.id.0, UserState::Idle).unwrap();
-                    db.set_user_state(chat, UserState::Idle).unwrap();
+                    let _ = db.delete_chat(msg.chat.id.0).await;
+                    db.set_user_state(msg.chat.id.0, UserState::Idle).await.unwrap();
+                    db.set_user_state(chat, UserState::Idle).await.unwrap();
+                    self_destruct::clear_dialog(&bot, &db, msg.chat.id.0, chat).await;
 
                     let reactions = [
                         InlineKeyboardButton::callback("👍", format!("like_{}", chat)),
@@ -479,104 +911,39 @@ pub async fn next(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
                         InlineKeyboardButton::callback("👍", format!("like_{}", msg.chat.id)),
                         InlineKeyboardButton::callback("👎", format!("dislike_{}", msg.chat.id)),
                     ];
-                    bot
+                    let sent = bot
                         .send_message(ChatId(chat), "Твой собеседник остановил диалог!!")
-                        .reply_markup(InlineKeyboardMarkup::new([reactions])).await?;
+                        .reply_markup(InlineKeyboardMarkup::new([reactions])).await;
+                    matchmaking::check_reachable(&db, chat, sent).await;
                 }
             }
-            let result = db.enqueue_user(
-                dialog.chat_id().0,
-                user.search_gender.unwrap(),
-                user.gender,
-                user.chat_type.as_ref().unwrap().clone()
-            );
-
-            if result.is_ok() {
-                let result = result.unwrap();
-                let cancel = [InlineKeyboardButton::callback("❌ Отменить", "cancel")];
-
-                if result != 0 {
-                    dialog.update(State::Dialog {
-                        interlocutor: result as u64,
-                    }).await?;
-                    let interlocutor = db.get_user(result).unwrap().unwrap();
-
-                    if user.is_premium {
-                        bot.send_message(
-                            dialog.chat_id(),
-                            format!(
-                                "🆔: {}\nПол: {}\nПсевдоним: {} \nВозраст: {}\n\nСобеседник найден!\n\n/next - чтобы найти нового собеседника\n/stop - чтобы остановить диалог",
-
-                                interlocutor.id,
-                                if interlocutor.gender == Gender::Male {
-                                    "Мужской ♂"
-                                } else {
-                                    "Женский ♀"
-                                },
-                                interlocutor.nickname,
-                                interlocutor.age
-                            )
-                        ).await?;
-                    } else {
-                        bot.send_message(
-                            dialog.chat_id(),
-                            format!(
-                                "Собеседник найден!\n\n/next - чтобы найти нового собеседника\n/stop - чтобы остановить диалог"
-                            )
-                        ).await?;
-                    }
-
-                    if interlocutor.is_premium {
-                        bot.send_message(
-                            ChatId(result),
-                            format!(
-                                "{} \n\nСобеседник найден!\n\n🆔: {}\nПол: {}\nПсевдоним: {} \nВозраст: {}\n\n/next - чтобы найти нового собеседника\n/stop - чтобы остановить диалог",
-                                if user.chat_type == Some(ChatType::Regular) {
-                                    "💬"
-                                } else {
-                                    "🔞"
-                                },
-                                user.id,
-                                if user.gender == Gender::Male {
-                                    "Мужской ♂"
-                                } else {
-                                    "Женский ♀"
-                                },
-                                user.nickname,
-                                user.age
-                            )
-                        ).await?;
-                    } else {
-                        bot.send_message(
-                            ChatId(result),
-                            format!(
-                                "Собеседник найден!\n\n/next - чтобы найти нового собеседника\n/stop - чтобы остановить диалог"
-                            )
-                        ).await?;
-                    }
-
-                    db.set_user_state(user.id, user_state::UserState::Dialog).unwrap();
-                    db.set_user_state(result, user_state::UserState::Dialog).unwrap();
-                } else {
-                    bot
-                        .send_message(dialog.chat_id(), "Ищу...")
-                        .reply_markup(InlineKeyboardMarkup::new([cancel])).await?;
-                    dialog.update(State::Search).await?;
-
-                    db.set_user_state(user.id, user_state::UserState::Search).unwrap();
-                }
-            } else {
-                bot.send_message(dialog.chat_id(), format!("Ой! Голова кружится...")).await?;
-            }
+            matchmaking::search(
+                &bot,
+                &dialog,
+                &db,
+                &matchmaker,
+                Searcher::new(
+                    &user,
+                    user.search_gender.unwrap(),
+                    user.chat_type.clone().unwrap(),
+                    user.nearby && user.region.is_some()
+                )
+            ).await?;
         }
     }
 
     Ok(())
 }
 
-pub async fn start(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
-    let db = DATABASE.get_or_init(|| TokioMutex::new(Database::new("db.db").unwrap())).lock().await;
-
+pub async fn start(
+    bot: Bot,
+    dialog: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>,
+    albums: Albums,
+    matchmaker: Matchmaker
+) -> HandlerResult {
     if let Some(txt) = msg.text() {
         if let Some(id) = txt.split("/start").nth(1) {
             let id = id.trim().parse::<i64>();
@@ -584,17 +951,17 @@ pub async fn start(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
             if id.is_ok() {
                 let id = id.unwrap();
 
-                let user = db.get_user(id);
+                let user = db.get_user(id).await;
                 if user.is_ok() {
                     let user = user.unwrap();
 
                     if user.is_some() {
                         let user = user.unwrap();
-                        let u = db.get_user(msg.chat.id.0);
+                        let u = db.get_user(msg.chat.id.0).await;
 
                         if u.is_err() || u.unwrap().is_none() {
-                            if user.referrals + 1 >= 10 {
-                                db.set_premium(user.id, true).unwrap();
+                            if user.referrals + 1 >= config.rules.referral_premium_threshold {
+                                db.set_premium(user.id, true).await.unwrap();
 
                                 let new_premium;
                                 if user.is_premium {
@@ -605,12 +972,13 @@ pub async fn start(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
                                         None => chrono::Utc::now(),
                                     };
                                     new_premium =
-                                        old_premium + chrono::Duration::try_days(7).unwrap();
+                                        old_premium +
+                                        chrono::Duration::try_days(config.rules.referral_premium_days).unwrap();
                                 } else {
                                     let now = chrono::Utc::now();
                                     new_premium = now + chrono::Duration::try_seconds(1).unwrap();
                                 }
-                                db.set_premium_until(user.id, new_premium.timestamp()).unwrap();
+                                db.set_premium_until(user.id, new_premium.timestamp()).await.unwrap();
 
                                 bot.send_message(ChatId(user.id), "Вы получили премиум 💎").await?;
                                 let _ = bot.send_message(
@@ -621,12 +989,14 @@ pub async fn start(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
                                     )
                                 ).await;
                             }
-                            let _ = db.increase_referral_count(user.id);
+                            let _ = db.increase_referral_count(user.id).await;
                             let _ = bot.send_message(
                                 ChatId(user.id),
                                 format!(
                                     "По вашей реферальной ссылке перешёл 1 человек! \n\nДо получения премиум 💎 осталось: {} человек",
-                                    10 - (user.referrals + 1)
+                                    config.rules.referral_premium_threshold.saturating_sub(
+                                        user.referrals + 1
+                                    )
                                 )
                             ).await;
                         }
@@ -636,10 +1006,26 @@ pub async fn start(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
         }
     }
 
-    let user = db.get_user(dialog.chat_id().0);
+    let user = db.get_user(dialog.chat_id().0).await;
 
     if user.is_ok() && user.as_ref().unwrap().is_some() {
-        idle(bot, dialog, msg).await?;
+        let user = user.unwrap().unwrap();
+
+        // Pressing Start again after unblocking the bot.
+        if !user.is_active {
+            db.set_user_active(user.id, true).await?;
+        }
+        // Registered before languages were recorded.
+        if user.language.is_none() {
+            let language = msg
+                .from()
+                .and_then(|from| from.language_code.as_deref())
+                .and_then(language::normalize);
+            if language.is_some() {
+                db.update_language(user.id, language.as_deref(), user.strict_language).await?;
+            }
+        }
+        idle(bot, dialog, msg, db, config, albums, matchmaker).await?;
     } else {
         bot.send_message(msg.chat.id, "Добро пожаловать в анонимный чат Sin!").await?;
         bot.send_message(msg.chat.id, "Нужно зарегестрироваться! Введи свой возраст: ").await?;
@@ -648,14 +1034,18 @@ pub async fn start(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
 
     Ok(())
 }
-pub async fn idle(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
+pub async fn idle(
+    bot: Bot,
+    dialog: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>,
+    albums: Albums,
+    matchmaker: Matchmaker
+) -> HandlerResult {
     if let Some(txt) = msg.text() {
         if txt.contains("search") {
-            let db = DATABASE.get_or_init(||
-                TokioMutex::new(Database::new("db.db").unwrap())
-            ).lock().await;
-
-            let user = db.get_user(dialog.chat_id().0);
+            let user = db.get_user(dialog.chat_id().0).await;
 
             if user.is_ok() && user.as_ref().unwrap().is_some() {
                 let user = user.unwrap().unwrap();
@@ -680,7 +1070,7 @@ pub async fn idle(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
                 return Ok(());
             }
 
-            let genders = ["Мужской ♂", "Женский ♀"].map(|product|
+            let genders = SearchGender::BUTTONS.map(|product|
                 InlineKeyboardButton::callback(product, product)
             );
             bot.send_message(dialog.chat_id(), "Теперь выбери пол собеседника")
@@ -688,11 +1078,34 @@ pub async fn idle(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
                 .unwrap();
             dialog.update(State::SearchChooseGender).await.unwrap();
         } else {
-            receive_message(bot, dialog, msg).await?;
+            receive_message(bot, dialog, msg, db, config, albums, matchmaker).await?;
         }
     } else {
-        receive_message(bot, dialog, msg).await?;
+        receive_message(bot, dialog, msg, db, config, albums, matchmaker).await?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_stats_reports_the_95th_percentile() {
+        let mut waits: Vec<_> = (1..=100)
+            .map(|waited| (Gender::Male, ChatType::Regular, waited))
+            .collect();
+        waits.extend((1..=20).map(|waited| (Gender::Female, ChatType::Regular, waited)));
+        waits.push((Gender::Female, ChatType::Vulgar, 7));
+
+        let lines: Vec<String> = wait_stats(&waits).lines().map(ToOwned::to_owned).collect();
+
+        assert_eq!(lines, [
+            "Мужской ♂ 💬: 50s / 95s (100)",
+            "Мужской ♂ 🔞: -",
+            "Женский ♀ 💬: 10s / 19s (20)",
+            "Женский ♀ 🔞: 7s / 7s (1)",
+        ]);
+    }
+}
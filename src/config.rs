@@ -0,0 +1,269 @@
+use std::{ env, fmt, fs, io };
+
+use serde::Deserialize;
+
+use crate::models::{ chat_type::ChatType, message_kind::MessageKind };
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// All bot settings. Read from the TOML file named by `SIN_CONFIG`
+/// (`config.toml` by default), then overridden from the environment.
+#[derive(Debug, Clone, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bot: BotConfig,
+    pub database: DatabaseConfig,
+    pub rules: RulesConfig,
+    pub relay: RelayConfig,
+    pub media: MediaConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotConfig {
+    pub token: String,
+    pub username: String,
+    pub admin: i64,
+    pub support_username: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub backend: DatabaseBackend,
+    pub path: String,
+    pub url: String,
+    pub pool_size: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseBackend {
+    Sqlite,
+    #[cfg(feature = "postgres")]
+    Postgres,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RulesConfig {
+    pub min_age: u8,
+    pub ban_reputation: i32,
+    pub referral_premium_threshold: u32,
+    pub referral_premium_days: i64,
+    /// Days of free premium for users who sign up during the spring promotion.
+    pub promo_premium_days: i64,
+    /// Seconds a searcher with interest tags holds out for a partner sharing
+    /// one before taking anyone compatible.
+    pub tag_wait: i64,
+}
+
+/// Which message kinds are passed on in each chat type.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    pub regular: Vec<MessageKind>,
+    pub vulgar: Vec<MessageKind>,
+}
+
+/// Defaults for how relayed media is delivered in each chat type. Users can
+/// override them with /media.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediaConfig {
+    pub regular: MediaSettings,
+    pub vulgar: MediaSettings,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediaSettings {
+    /// Send media with `protect_content`, so it can't be forwarded or saved.
+    pub protect_content: bool,
+    /// Blur photos and videos behind a spoiler.
+    pub spoiler: bool,
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        BotConfig {
+            token: String::new(),
+            username: "s1nchat_bot".to_owned(),
+            admin: 0,
+            support_username: "s1nchat_admin".to_owned(),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            backend: DatabaseBackend::Sqlite,
+            path: "db.db".to_owned(),
+            url: String::new(),
+            pool_size: 8,
+        }
+    }
+}
+
+impl Default for RulesConfig {
+    fn default() -> Self {
+        RulesConfig {
+            min_age: 12,
+            ban_reputation: -20,
+            referral_premium_threshold: 10,
+            referral_premium_days: 7,
+            promo_premium_days: 7,
+            tag_wait: 30,
+        }
+    }
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig {
+            regular: MessageKind::ALL.to_vec(),
+            vulgar: MessageKind::ALL.to_vec(),
+        }
+    }
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        MediaConfig {
+            regular: MediaSettings::default(),
+            vulgar: MediaSettings { protect_content: true, spoiler: true },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, io::Error),
+    Parse(toml::de::Error),
+    Env(&'static str, String),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "can't read config {}: {}", path, e),
+            ConfigError::Parse(e) => write!(f, "invalid config: {}", e),
+            ConfigError::Env(name, value) => write!(f, "invalid value of {}: {:?}", name, value),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads, overrides and validates the configuration. A missing file is not
+    /// an error as long as the environment supplies the required settings.
+    pub fn load() -> Result<Self, ConfigError> {
+        let path = env::var("SIN_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_owned());
+
+        let mut config = match fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content).map_err(ConfigError::Parse)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && env::var("SIN_CONFIG").is_err() => {
+                Config::default()
+            }
+            Err(e) => {
+                return Err(ConfigError::Io(path, e));
+            }
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Ok(token) = env::var("TELOXIDE_TOKEN") {
+            self.bot.token = token;
+        }
+        if let Ok(admin) = env::var("ADMIN") {
+            self.bot.admin = admin.trim().parse().map_err(|_| ConfigError::Env("ADMIN", admin))?;
+        }
+        if let Ok(username) = env::var("SIN_BOT_USERNAME") {
+            self.bot.username = username;
+        }
+        if let Ok(path) = env::var("SIN_DATABASE_PATH") {
+            self.database.path = path;
+        }
+        if let Ok(url) = env::var("SIN_DATABASE_URL") {
+            self.database.url = url;
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.bot.token.trim().is_empty() {
+            return Err(ConfigError::Invalid("bot.token is not set"));
+        }
+        if self.bot.admin == 0 {
+            return Err(ConfigError::Invalid("bot.admin is not set"));
+        }
+        if self.bot.username.is_empty() || self.bot.username.starts_with('@') {
+            return Err(ConfigError::Invalid("bot.username must be set without a leading @"));
+        }
+        match self.database.backend {
+            DatabaseBackend::Sqlite if self.database.path.is_empty() => {
+                return Err(ConfigError::Invalid("database.path is empty"));
+            }
+            #[cfg(feature = "postgres")]
+            DatabaseBackend::Postgres if self.database.url.is_empty() => {
+                return Err(ConfigError::Invalid("database.url is empty"));
+            }
+            _ => {}
+        }
+        if self.database.pool_size == 0 {
+            return Err(ConfigError::Invalid("database.pool_size must be positive"));
+        }
+        if self.rules.min_age == 0 {
+            return Err(ConfigError::Invalid("rules.min_age must be positive"));
+        }
+        if self.rules.ban_reputation >= 0 {
+            return Err(ConfigError::Invalid("rules.ban_reputation must be negative"));
+        }
+        if self.rules.referral_premium_threshold == 0 {
+            return Err(ConfigError::Invalid("rules.referral_premium_threshold must be positive"));
+        }
+        if self.rules.referral_premium_days <= 0 {
+            return Err(ConfigError::Invalid("rules.referral_premium_days must be positive"));
+        }
+        if self.rules.promo_premium_days <= 0 {
+            return Err(ConfigError::Invalid("rules.promo_premium_days must be positive"));
+        }
+        if self.rules.tag_wait < 0 {
+            return Err(ConfigError::Invalid("rules.tag_wait must not be negative"));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_admin(&self, chat_id: i64) -> bool {
+        chat_id == self.bot.admin
+    }
+
+    pub fn relay_allowed(&self, chat_type: &ChatType, kind: MessageKind) -> bool {
+        match chat_type {
+            ChatType::Regular => self.relay.regular.contains(&kind),
+            ChatType::Vulgar => self.relay.vulgar.contains(&kind),
+        }
+    }
+
+    pub fn media_defaults(&self, chat_type: &ChatType) -> MediaSettings {
+        match chat_type {
+            ChatType::Regular => self.media.regular,
+            ChatType::Vulgar => self.media.vulgar,
+        }
+    }
+
+    pub fn referral_link(&self, user_id: i64) -> String {
+        format!("https://t.me/{}?start={}", self.bot.username, user_id)
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    Pool(r2d2::Error),
+    Task(tokio::task::JoinError),
+    #[cfg(feature = "postgres")]
+    Postgres(tokio_postgres::Error),
+    #[cfg(feature = "postgres")]
+    PostgresPool(deadpool_postgres::PoolError),
+    #[cfg(feature = "postgres")]
+    PostgresConfig(deadpool_postgres::CreatePoolError),
+    SchemaTooNew {
+        found: u32,
+        supported: u32,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sqlite(e) => write!(f, "sqlite error: {}", e),
+            Error::Pool(e) => write!(f, "connection pool error: {}", e),
+            Error::Task(e) => write!(f, "database task failed: {}", e),
+            #[cfg(feature = "postgres")]
+            Error::Postgres(e) => write!(f, "postgres error: {}", e),
+            #[cfg(feature = "postgres")]
+            Error::PostgresPool(e) => write!(f, "postgres pool error: {}", e),
+            #[cfg(feature = "postgres")]
+            Error::PostgresConfig(e) => write!(f, "invalid postgres pool config: {}", e),
+            Error::SchemaTooNew { found, supported } =>
+                write!(
+                    f,
+                    "database schema version {} is newer than the latest supported version {}",
+                    found,
+                    supported
+                ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(value: rusqlite::Error) -> Self {
+        Error::Sqlite(value)
+    }
+}
+
+impl From<r2d2::Error> for Error {
+    fn from(value: r2d2::Error) -> Self {
+        Error::Pool(value)
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(value: tokio::task::JoinError) -> Self {
+        Error::Task(value)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<tokio_postgres::Error> for Error {
+    fn from(value: tokio_postgres::Error) -> Self {
+        Error::Postgres(value)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<deadpool_postgres::PoolError> for Error {
+    fn from(value: deadpool_postgres::PoolError) -> Self {
+        Error::PostgresPool(value)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<deadpool_postgres::CreatePoolError> for Error {
+    fn from(value: deadpool_postgres::CreatePoolError) -> Self {
+        Error::PostgresConfig(value)
+    }
+}
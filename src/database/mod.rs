@@ -0,0 +1,492 @@
+pub mod error;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod sqlite;
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{
+    config::{ DatabaseBackend, DatabaseConfig },
+    models::{
+        chat_type::ChatType,
+        gender::Gender,
+        search_gender::SearchGender,
+        searcher::Searcher,
+        tag::Tag,
+        user::User,
+    },
+    user_state::UserState,
+};
+
+pub use error::{ Error, Result };
+
+/// Shared handle to whichever backend the config selected. This is what
+/// handlers receive through `dptree::deps!`.
+pub type Database = Arc<dyn StorageBackend>;
+
+/// Everything the bot persists: users, the search queue, active chats,
+/// reputation and dialogue state.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    async fn get_total_users(&self) -> Result<usize>;
+
+    async fn get_gender_count(&self, gender: Gender) -> Result<usize>;
+
+    async fn get_female_count(&self) -> Result<usize> {
+        self.get_gender_count(Gender::Female).await
+    }
+
+    async fn get_male_count(&self) -> Result<usize> {
+        self.get_gender_count(Gender::Male).await
+    }
+
+    async fn get_total_chats(&self) -> Result<usize>;
+
+    async fn get_chat(&self, user_id: i64) -> Result<Option<i64>>;
+
+    async fn get_chat_type(&self, user_id: i64) -> Result<Option<ChatType>>;
+
+    async fn get_queue_count(&self) -> Result<usize>;
+
+    async fn get_gender_queue_count(&self, gender: Gender) -> Result<usize>;
+
+    async fn get_female_queue_count(&self) -> Result<usize> {
+        self.get_gender_queue_count(Gender::Female).await
+    }
+
+    async fn get_male_queue_count(&self) -> Result<usize> {
+        self.get_gender_queue_count(Gender::Male).await
+    }
+
+    /// Returns `true` if the user dropped to `ban_reputation` and got banned.
+    async fn decrease_reputation(
+        &self,
+        user_id: i64,
+        amount: i32,
+        ban_reputation: i32
+    ) -> Result<bool>;
+
+    /// Returns `false` if the user got unbanned by climbing back above `ban_reputation`.
+    async fn increase_reputation(
+        &self,
+        user_id: i64,
+        amount: i32,
+        ban_reputation: i32
+    ) -> Result<bool>;
+
+    async fn ban_user(&self, user_id: i64) -> Result<()>;
+
+    async fn unban_user(&self, user_id: i64) -> Result<()>;
+
+    async fn add_user(&self, user: &User) -> Result<()>;
+
+    async fn get_user(&self, user_id: i64) -> Result<Option<User>>;
+
+    async fn get_all_users(&self) -> Result<Vec<User>>;
+
+    async fn get_top_referral_users(&self, limit: usize) -> Result<Vec<User>>;
+
+    async fn get_top_reputation_users(&self, limit: usize) -> Result<Vec<User>>;
+
+    async fn delete_user_by_id(&self, user_id: i64) -> Result<()>;
+
+    async fn increase_referral_count(&self, user_id: i64) -> Result<()>;
+
+    async fn set_user_state(&self, user_id: i64, new_state: UserState) -> Result<()>;
+
+    async fn set_premium(&self, user_id: i64, is_premium: bool) -> Result<()>;
+
+    async fn set_premium_until(&self, user_id: i64, until: i64) -> Result<()>;
+
+    /// Stores the user's overrides of the chat type's media defaults; `None`
+    /// falls back to the default.
+    async fn update_media_settings(
+        &self,
+        user_id: i64,
+        protect_content: Option<bool>,
+        media_spoiler: Option<bool>
+    ) -> Result<()>;
+
+    /// Inactive users blocked the bot or deleted their account and are left
+    /// out of broadcasts.
+    async fn set_user_active(&self, user_id: i64, is_active: bool) -> Result<()>;
+
+    /// Sets how long the user's photos and videos live after being relayed;
+    /// `Some(0)` is view-once and `None` turns it off.
+    async fn update_self_destruct(&self, user_id: i64, seconds: Option<i32>) -> Result<()>;
+
+    /// Sets the inclusive age range the user's partners must fall in; `None`
+    /// accepts any age.
+    async fn update_partner_age(&self, user_id: i64, range: Option<(u8, u8)>) -> Result<()>;
+
+    /// Sets the user's language and whether partners must speak it.
+    async fn update_language(&self, user_id: i64, language: Option<&str>, strict: bool) -> Result<()>;
+
+    /// Stores the coarse region the user shared, or forgets it with `None`,
+    /// including on their queue entry.
+    async fn update_region(&self, user_id: i64, region: Option<&str>) -> Result<()>;
+
+    async fn update_user_nickname(&self, user_id: i64, new_nickname: &str) -> Result<()>;
+
+    async fn update_user_age(&self, user_id: i64, new_age: u8) -> Result<()>;
+
+    async fn update_user_gender(&self, user_id: i64, new_gender: Gender) -> Result<()>;
+
+    /// Remembers the filters so `/next` can search again with them.
+    async fn update_search_filters(
+        &self,
+        user_id: i64,
+        search_gender: SearchGender,
+        chat_type: ChatType,
+        nearby: bool
+    ) -> Result<()>;
+
+    async fn add_to_queue(&self, searcher: &Searcher) -> Result<()>;
+
+    /// Waiting searchers with their tags, oldest first.
+    async fn get_queue(&self) -> Result<Vec<Searcher>>;
+
+    /// The interest tag catalogue, by name.
+    async fn get_tags(&self) -> Result<Vec<Tag>>;
+
+    /// Returns `false` if the catalogue already has a tag with this name.
+    async fn add_tag(&self, name: &str) -> Result<bool>;
+
+    /// Removes the tag from the catalogue and from every user who picked it.
+    /// Returns `false` if there was no such tag.
+    async fn remove_tag(&self, name: &str) -> Result<bool>;
+
+    async fn get_user_tags(&self, user_id: i64) -> Result<Vec<Tag>>;
+
+    /// Adds or removes one of the user's tags. Ids missing from the catalogue
+    /// are ignored.
+    async fn set_user_tag(&self, user_id: i64, tag_id: i32, selected: bool) -> Result<()>;
+
+    /// Records how long the searcher waited before being matched at `matched_at`.
+    async fn add_wait_time(&self, searcher: &Searcher, matched_at: i64) -> Result<()>;
+
+    /// `(gender, chat type, seconds waited)` for matches made since `since`.
+    async fn get_wait_times(&self, since: i64) -> Result<Vec<(Gender, ChatType, i64)>>;
+
+    async fn dequeue_user(&self, user_id: i64) -> Result<()>;
+
+    /// Ends the user's chat, returning the interlocutor it was with. The
+    /// chat's message links go with it.
+    async fn delete_chat(&self, user_id: i64) -> Result<Option<i64>>;
+
+    /// Pairs two users, taking `partner_id` off the queue in the same transaction.
+    /// Returns `false` and creates nothing if the partner is no longer queued,
+    /// either user is banned or gone, or either is already in a chat.
+    async fn create_chat(&self, user_id: i64, partner_id: i64, chat_type: ChatType) -> Result<bool>;
+
+    /// Removes a chat row together with the message links and pending
+    /// self-destructs between its two users, in one transaction.
+    async fn delete_chat_by_id(&self, chat_id: i64) -> Result<()>;
+
+    /// Every chat row as `(id, chat_one, chat_two)`, oldest first.
+    async fn get_chats(&self) -> Result<Vec<(i64, i64, i64)>>;
+
+    /// Remembers that `sender_msg` in `sender_chat` was relayed as `receiver_msg`.
+    async fn add_message_link(
+        &self,
+        sender_chat: i64,
+        sender_msg: i32,
+        receiver_chat: i64,
+        receiver_msg: i32
+    ) -> Result<()>;
+
+    /// The copy of a message on the other side of the chat, whichever side it
+    /// was sent from, as `(chat, message id)`.
+    async fn get_linked_message(&self, chat_id: i64, msg_id: i32) -> Result<Option<(i64, i32)>>;
+
+    /// The partner's copy of a message the user sent, as `(chat, message id)`.
+    async fn get_relayed_copy(&self, sender_chat: i64, sender_msg: i32) -> Result<Option<(i64, i32)>>;
+
+    /// The newest still-linked message the user sent, as
+    /// `(sender message id, receiver chat, receiver message id)`.
+    async fn get_last_relayed_message(&self, sender_chat: i64) -> Result<Option<(i32, i64, i32)>>;
+
+    async fn delete_message_link(&self, sender_chat: i64, sender_msg: i32) -> Result<()>;
+
+    /// Marks the receiver's copy of a self-destructing message for deletion:
+    /// at `delete_at`, or for view-once copies (`None`) once the message they
+    /// share `anchor` with is marked viewed.
+    async fn add_self_destruct(
+        &self,
+        receiver_chat: i64,
+        receiver_msg: i32,
+        sender_chat: i64,
+        delete_at: Option<i64>,
+        anchor: Option<i32>
+    ) -> Result<()>;
+
+    /// Timed copies still waiting, as `(chat, message id, delete at)`.
+    async fn get_self_destruct_timers(&self) -> Result<Vec<(i64, i32, i64)>>;
+
+    /// Forgets a pending copy. Returns `false` if it was already gone.
+    async fn remove_self_destruct(&self, receiver_chat: i64, receiver_msg: i32) -> Result<bool>;
+
+    /// The anchor of a pending view-once copy, or `None` for timed or
+    /// untracked messages.
+    async fn get_view_once_anchor(&self, receiver_chat: i64, receiver_msg: i32) -> Result<Option<i32>>;
+
+    /// Forgets the view-once copies sharing `anchor` and returns their ids.
+    async fn take_view_once(&self, receiver_chat: i64, anchor: i32) -> Result<Vec<i32>>;
+
+    /// Forgets every pending copy the two users sent each other and returns
+    /// them as `(chat, message id)`.
+    async fn take_dialog_self_destructs(&self, user_id: i64, partner_id: i64) -> Result<Vec<(i64, i32)>>;
+
+    async fn get_dialogue_states(&self) -> Result<Vec<(i64, String)>>;
+
+    async fn get_dialogue_state(&self, chat_id: i64) -> Result<Option<String>>;
+
+    async fn set_dialogue_state(&self, chat_id: i64, state: String) -> Result<()>;
+
+    async fn remove_dialogue_state(&self, chat_id: i64) -> Result<()>;
+
+    async fn add_restart_notice(&self, user_id: i64) -> Result<()>;
+
+    async fn get_restart_notices(&self) -> Result<Vec<i64>>;
+
+    async fn clear_restart_notices(&self) -> Result<()>;
+
+    /// Makes sure everything written so far is durable before the process exits.
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A single forward-only schema change. Versions must be strictly increasing
+/// and mean the same thing on every backend.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// Opens the configured backend and brings its schema up to date.
+pub async fn connect(config: &DatabaseConfig) -> Result<Database> {
+    match config.backend {
+        DatabaseBackend::Sqlite =>
+            Ok(Arc::new(sqlite::SqliteStorage::new(&config.path, config.pool_size)?)),
+        #[cfg(feature = "postgres")]
+        DatabaseBackend::Postgres =>
+            Ok(Arc::new(postgres::PostgresStorage::new(&config.url, config.pool_size).await?)),
+    }
+}
+
+/// One suite for every backend, so they can't drift apart. It only touches
+/// its own users and tags, which lets it run against a shared database.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE: i64 = 7_000_000_001;
+    const TWO: i64 = 7_000_000_002;
+    const THREE: i64 = 7_000_000_003;
+
+    async fn reset(db: &Database) {
+        for id in [ONE, TWO, THREE] {
+            db.delete_chat(id).await.unwrap();
+            db.dequeue_user(id).await.unwrap();
+            db.delete_user_by_id(id).await.unwrap();
+            db.remove_dialogue_state(id).await.unwrap();
+        }
+        db.take_dialog_self_destructs(ONE, TWO).await.unwrap();
+        for name in ["suite-a", "suite-b"] {
+            db.remove_tag(name).await.unwrap();
+        }
+    }
+
+    async fn add_user(db: &Database, id: i64, gender: Gender) -> User {
+        let user = User::new(id, 20, format!("user{}", id), gender);
+        db.add_user(&user).await.unwrap();
+        user
+    }
+
+    fn searcher(user: &User, search_gender: SearchGender) -> Searcher {
+        Searcher::new(user, search_gender, ChatType::Regular, false)
+    }
+
+    async fn users(db: &Database) {
+        let total = db.get_total_users().await.unwrap();
+        add_user(db, ONE, Gender::Male).await;
+
+        assert_eq!(db.get_total_users().await.unwrap(), total + 1);
+
+        db.update_user_age(ONE, 30).await.unwrap();
+        db.update_user_gender(ONE, Gender::Female).await.unwrap();
+        db.update_user_nickname(ONE, "renamed").await.unwrap();
+        db.set_user_state(ONE, UserState::Search).await.unwrap();
+        db.update_partner_age(ONE, Some((18, 25))).await.unwrap();
+        db.update_language(ONE, Some("ru"), true).await.unwrap();
+        db.update_region(ONE, Some("ucfv")).await.unwrap();
+
+        let user = db.get_user(ONE).await.unwrap().unwrap();
+        assert_eq!((user.age, user.gender, user.nickname.as_str()), (30, Gender::Female, "renamed"));
+        assert_eq!(user.state, UserState::Search);
+        assert_eq!(user.partner_age, Some((18, 25)));
+        assert_eq!((user.language.as_deref(), user.strict_language), (Some("ru"), true));
+        assert_eq!(user.region.as_deref(), Some("ucfv"));
+
+        db.delete_user_by_id(ONE).await.unwrap();
+        assert!(db.get_user(ONE).await.unwrap().is_none());
+    }
+
+    async fn reputation(db: &Database) {
+        add_user(db, ONE, Gender::Male).await;
+
+        assert!(!db.decrease_reputation(ONE, 1, -2).await.unwrap());
+        assert!(db.decrease_reputation(ONE, 1, -2).await.unwrap());
+        assert!(db.get_user(ONE).await.unwrap().unwrap().is_banned);
+
+        // Back to -1 is above the threshold again.
+        assert!(!db.increase_reputation(ONE, 1, -2).await.unwrap());
+        let user = db.get_user(ONE).await.unwrap().unwrap();
+        assert_eq!((user.reputation, user.is_banned), (-1, false));
+
+        assert!(db.decrease_reputation(ONE, 5, -2).await.unwrap());
+        // -6 + 1 is still at or below the threshold.
+        assert!(db.increase_reputation(ONE, 1, -2).await.unwrap());
+        assert!(db.get_user(ONE).await.unwrap().unwrap().is_banned);
+
+        // Concurrent votes must all land.
+        let mut votes = tokio::task::JoinSet::new();
+        for _ in 0..20 {
+            let db = db.clone();
+            votes.spawn(async move { db.increase_reputation(ONE, 1, -100).await });
+        }
+        while let Some(vote) = votes.join_next().await {
+            vote.unwrap().unwrap();
+        }
+        assert_eq!(db.get_user(ONE).await.unwrap().unwrap().reputation, 15);
+    }
+
+    async fn queue_and_chats(db: &Database) {
+        let one = add_user(db, ONE, Gender::Male).await;
+        add_user(db, TWO, Gender::Female).await;
+        let three = add_user(db, THREE, Gender::Female).await;
+        let queued = db.get_queue_count().await.unwrap();
+
+        db.add_to_queue(&searcher(&one, SearchGender::Female)).await.unwrap();
+        assert_eq!(db.get_queue_count().await.unwrap(), queued + 1);
+        assert!(db.get_queue().await.unwrap().iter().any(|searcher| searcher.user_id == ONE));
+
+        // The partner has to be queued and neither side banned.
+        assert!(!db.create_chat(TWO, THREE, ChatType::Regular).await.unwrap());
+        db.ban_user(TWO).await.unwrap();
+        assert!(!db.create_chat(TWO, ONE, ChatType::Regular).await.unwrap());
+        db.unban_user(TWO).await.unwrap();
+
+        assert!(db.create_chat(TWO, ONE, ChatType::Regular).await.unwrap());
+        assert_eq!(db.get_queue_count().await.unwrap(), queued);
+        assert_eq!(db.get_chat(ONE).await.unwrap(), Some(TWO));
+        assert_eq!(db.get_chat(TWO).await.unwrap(), Some(ONE));
+        assert_eq!(db.get_chat_type(ONE).await.unwrap(), Some(ChatType::Regular));
+
+        db.add_to_queue(&searcher(&three, SearchGender::Male)).await.unwrap();
+        assert!(!db.create_chat(ONE, THREE, ChatType::Regular).await.unwrap());
+
+        db.add_message_link(ONE, 10, TWO, 20).await.unwrap();
+        assert_eq!(db.get_linked_message(TWO, 20).await.unwrap(), Some((ONE, 10)));
+        assert_eq!(db.get_relayed_copy(ONE, 10).await.unwrap(), Some((TWO, 20)));
+        assert_eq!(db.get_last_relayed_message(ONE).await.unwrap(), Some((10, TWO, 20)));
+
+        assert_eq!(db.delete_chat(ONE).await.unwrap(), Some(TWO));
+        assert_eq!(db.delete_chat(TWO).await.unwrap(), None);
+        assert_eq!(db.get_relayed_copy(ONE, 10).await.unwrap(), None);
+
+        // Removing a chat by id takes its links and self-destructs too.
+        db.add_to_queue(&searcher(&one, SearchGender::Female)).await.unwrap();
+        assert!(db.create_chat(TWO, ONE, ChatType::Regular).await.unwrap());
+        db.add_message_link(ONE, 11, TWO, 21).await.unwrap();
+        db.add_self_destruct(TWO, 21, ONE, Some(i64::MAX), None).await.unwrap();
+
+        let (id, _, _) = db
+            .get_chats().await.unwrap()
+            .into_iter()
+            .find(|&(_, chat_one, _)| chat_one == TWO)
+            .unwrap();
+        db.delete_chat_by_id(id).await.unwrap();
+        assert_eq!(db.get_chat(ONE).await.unwrap(), None);
+        assert_eq!(db.get_relayed_copy(ONE, 11).await.unwrap(), None);
+        assert!(!db.remove_self_destruct(TWO, 21).await.unwrap());
+    }
+
+    async fn tags(db: &Database) {
+        add_user(db, ONE, Gender::Male).await;
+
+        assert!(db.add_tag("suite-a").await.unwrap());
+        assert!(!db.add_tag("suite-a").await.unwrap());
+        assert!(db.add_tag("suite-b").await.unwrap());
+
+        let catalogue = db.get_tags().await.unwrap();
+        let tag = catalogue.iter().find(|tag| tag.name == "suite-a").unwrap();
+
+        db.set_user_tag(ONE, tag.id, true).await.unwrap();
+        assert_eq!(db.get_user_tags(ONE).await.unwrap(), vec![tag.clone()]);
+
+        assert!(db.remove_tag("suite-a").await.unwrap());
+        assert!(!db.remove_tag("suite-a").await.unwrap());
+        assert!(db.get_user_tags(ONE).await.unwrap().is_empty());
+    }
+
+    async fn self_destructs(db: &Database) {
+        db.add_self_destruct(TWO, 20, ONE, None, Some(20)).await.unwrap();
+        db.add_self_destruct(TWO, 21, ONE, Some(i64::MAX), None).await.unwrap();
+
+        assert_eq!(db.get_view_once_anchor(TWO, 20).await.unwrap(), Some(20));
+        assert_eq!(db.get_view_once_anchor(TWO, 21).await.unwrap(), None);
+        assert_eq!(db.take_view_once(TWO, 20).await.unwrap(), vec![20]);
+        assert_eq!(db.take_dialog_self_destructs(ONE, TWO).await.unwrap(), vec![(TWO, 21)]);
+    }
+
+    async fn dialogue_states(db: &Database) {
+        db.set_dialogue_state(ONE, "first".to_string()).await.unwrap();
+        db.set_dialogue_state(ONE, "second".to_string()).await.unwrap();
+        assert_eq!(db.get_dialogue_state(ONE).await.unwrap().as_deref(), Some("second"));
+
+        db.remove_dialogue_state(ONE).await.unwrap();
+        assert_eq!(db.get_dialogue_state(ONE).await.unwrap(), None);
+    }
+
+    async fn suite(db: Database) {
+        reset(&db).await;
+        users(&db).await;
+        reset(&db).await;
+        reputation(&db).await;
+        reset(&db).await;
+        queue_and_chats(&db).await;
+        reset(&db).await;
+        tags(&db).await;
+        reset(&db).await;
+        self_destructs(&db).await;
+        reset(&db).await;
+        dialogue_states(&db).await;
+        reset(&db).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite() {
+        let path = std::env::temp_dir().join(format!("sin-suite-{}.db", std::process::id()));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+
+        suite(Arc::new(sqlite::SqliteStorage::new(path.to_str().unwrap(), 4).unwrap())).await;
+    }
+
+    /// Runs when `SIN_TEST_POSTGRES_URL` points at a scratch database.
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    async fn postgres() {
+        let Ok(url) = std::env::var("SIN_TEST_POSTGRES_URL") else {
+            return;
+        };
+
+        suite(Arc::new(postgres::PostgresStorage::new(&url, 4).await.unwrap())).await;
+    }
+}
@@ -0,0 +1,196 @@
+use deadpool_postgres::Client;
+
+use crate::database::{ Error, Migration, Result };
+
+/// Versions line up with the SQLite migrations so `schema_version` means the
+/// same thing on both backends.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        sql: "CREATE TABLE IF NOT EXISTS users (
+                  id BIGINT PRIMARY KEY,
+                  nickname TEXT NOT NULL,
+                  age SMALLINT NOT NULL,
+                  gender INTEGER NOT NULL,
+                  search_gender INTEGER NOT NULL DEFAULT 0,
+                  chat_type INTEGER NOT NULL DEFAULT 0,
+                  state INTEGER NOT NULL DEFAULT 0,
+                  reputation INTEGER NOT NULL DEFAULT 0,
+                  is_banned BOOLEAN NOT NULL DEFAULT FALSE,
+                  referrals INTEGER NOT NULL DEFAULT 0,
+                  is_premium BOOLEAN NOT NULL DEFAULT FALSE,
+                  premium_until BIGINT NOT NULL DEFAULT 0
+              );
+
+              CREATE TABLE IF NOT EXISTS queue (
+                  user_id BIGINT PRIMARY KEY,
+                  search_gender INTEGER NOT NULL DEFAULT 0,
+                  searcher_gender INTEGER NOT NULL,
+                  chat_type INTEGER NOT NULL DEFAULT 0
+              );
+
+              CREATE TABLE IF NOT EXISTS chats (
+                  id BIGSERIAL PRIMARY KEY,
+                  chat_one BIGINT NOT NULL UNIQUE,
+                  chat_two BIGINT NOT NULL UNIQUE,
+                  chat_type INTEGER NOT NULL DEFAULT 0
+              );
+
+              CREATE TABLE IF NOT EXISTS dialogues (
+                  chat_id BIGINT PRIMARY KEY,
+                  state TEXT NOT NULL
+              );",
+    },
+    Migration {
+        version: 2,
+        description: "store genders as integers and tighten column types",
+        // The Postgres schema started out normalised.
+        sql: "",
+    },
+    Migration {
+        version: 3,
+        description: "remember who to notify after a restart",
+        sql: "CREATE TABLE restart_notices (
+                  user_id BIGINT PRIMARY KEY
+              );",
+    },
+    Migration {
+        version: 4,
+        description: "record queue join times and match wait times",
+        sql: "ALTER TABLE queue ADD COLUMN joined_at BIGINT NOT NULL DEFAULT 0;
+
+              CREATE TABLE wait_times (
+                  id BIGSERIAL PRIMARY KEY,
+                  user_id BIGINT NOT NULL,
+                  gender INTEGER NOT NULL,
+                  chat_type INTEGER NOT NULL,
+                  waited BIGINT NOT NULL,
+                  matched_at BIGINT NOT NULL
+              );
+
+              CREATE INDEX wait_times_matched_at ON wait_times (matched_at);",
+    },
+    Migration {
+        version: 5,
+        description: "map relayed messages between the two sides of a chat",
+        sql: "CREATE TABLE message_links (
+                  sender_chat BIGINT NOT NULL,
+                  sender_msg INTEGER NOT NULL,
+                  receiver_chat BIGINT NOT NULL,
+                  receiver_msg INTEGER NOT NULL,
+                  PRIMARY KEY (sender_chat, sender_msg)
+              );
+
+              CREATE INDEX message_links_receiver ON message_links (receiver_chat, receiver_msg);",
+    },
+    Migration {
+        version: 6,
+        description: "let users override how relayed media is delivered to them",
+        sql: "ALTER TABLE users ADD COLUMN protect_content BOOLEAN;
+              ALTER TABLE users ADD COLUMN media_spoiler BOOLEAN;",
+    },
+    Migration {
+        version: 7,
+        description: "self-destructing media",
+        sql: "ALTER TABLE users ADD COLUMN self_destruct INTEGER;
+
+              CREATE TABLE self_destructs (
+                  receiver_chat BIGINT NOT NULL,
+                  receiver_msg INTEGER NOT NULL,
+                  sender_chat BIGINT NOT NULL,
+                  delete_at BIGINT,
+                  anchor INTEGER,
+                  PRIMARY KEY (receiver_chat, receiver_msg)
+              );",
+    },
+    Migration {
+        version: 8,
+        description: "mark users who blocked the bot or deleted their account",
+        sql: "ALTER TABLE users ADD COLUMN is_active BOOLEAN NOT NULL DEFAULT TRUE;",
+    },
+    Migration {
+        version: 9,
+        description: "partner age ranges",
+        sql: "ALTER TABLE users ADD COLUMN min_partner_age SMALLINT;
+              ALTER TABLE users ADD COLUMN max_partner_age SMALLINT;
+
+              ALTER TABLE queue ADD COLUMN age SMALLINT NOT NULL DEFAULT 0;
+              ALTER TABLE queue ADD COLUMN min_partner_age SMALLINT;
+              ALTER TABLE queue ADD COLUMN max_partner_age SMALLINT;
+              UPDATE queue SET age = users.age FROM users WHERE users.id = queue.user_id;",
+    },
+    Migration {
+        version: 10,
+        description: "interest tags",
+        sql: "CREATE TABLE tags (
+                  id SERIAL PRIMARY KEY,
+                  name TEXT NOT NULL UNIQUE
+              );
+
+              CREATE TABLE user_tags (
+                  user_id BIGINT NOT NULL,
+                  tag_id INTEGER NOT NULL,
+                  PRIMARY KEY (user_id, tag_id)
+              );
+              CREATE INDEX user_tags_tag ON user_tags (tag_id);",
+    },
+    Migration {
+        version: 11,
+        description: "user languages",
+        sql: "ALTER TABLE users ADD COLUMN language TEXT;
+              ALTER TABLE users ADD COLUMN strict_language BOOLEAN NOT NULL DEFAULT FALSE;
+
+              ALTER TABLE queue ADD COLUMN language TEXT;
+              ALTER TABLE queue ADD COLUMN strict_language BOOLEAN NOT NULL DEFAULT FALSE;",
+    },
+    Migration {
+        version: 12,
+        description: "coarse user regions for nearby search",
+        sql: "ALTER TABLE users ADD COLUMN region TEXT;
+              ALTER TABLE users ADD COLUMN nearby BOOLEAN NOT NULL DEFAULT FALSE;
+
+              ALTER TABLE queue ADD COLUMN region TEXT;
+              ALTER TABLE queue ADD COLUMN nearby BOOLEAN NOT NULL DEFAULT FALSE;",
+    },
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub async fn run(client: &mut Client) -> Result<()> {
+    client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+              version INTEGER PRIMARY KEY,
+              description TEXT NOT NULL,
+              applied_at BIGINT NOT NULL
+              )"
+    ).await?;
+
+    let row = client.query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[]).await?;
+    let current = row.get::<_, i32>(0) as u32;
+    let supported = latest_version();
+
+    if current > supported {
+        return Err(Error::SchemaTooNew { found: current, supported });
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        log::info!("Applying migration {}: {}", migration.version, migration.description);
+
+        let tx = client.transaction().await?;
+        tx.batch_execute(migration.sql).await?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES ($1, $2, $3)",
+            &[
+                &(migration.version as i32),
+                &migration.description,
+                &chrono::Utc::now().timestamp(),
+            ]
+        ).await?;
+        tx.commit().await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,827 @@
+pub mod migrations;
+
+use async_trait::async_trait;
+use deadpool_postgres::{ Object, Pool, PoolConfig, Runtime };
+use tokio_postgres::{ NoTls, Row };
+
+use super::{ Result, StorageBackend };
+use crate::{
+    models::{
+        chat_type::ChatType,
+        gender::Gender,
+        search_gender::SearchGender,
+        searcher::Searcher,
+        tag::Tag,
+        user::User,
+    },
+    user_state::UserState,
+};
+
+const USER_COLUMNS: &str =
+    "id, nickname, age, gender, state, reputation, is_banned, search_gender, chat_type, referrals, is_premium, premium_until, protect_content, media_spoiler, self_destruct, is_active, min_partner_age, max_partner_age, language, strict_language, region, nearby";
+
+fn user_from_row(row: &Row) -> User {
+    let age: i16 = row.get(2);
+    let referrals: i32 = row.get(9);
+
+    User {
+        id: row.get(0),
+        nickname: row.get(1),
+        age: age as u8,
+        gender: Gender::from(row.get::<_, i32>(3)),
+        state: UserState::from(row.get::<_, i32>(4)),
+        reputation: row.get(5),
+        is_banned: row.get(6),
+        search_gender: Some(SearchGender::from(row.get::<_, i32>(7))),
+        chat_type: Some(ChatType::from(row.get::<_, i32>(8))),
+        referrals: referrals as u32,
+        is_premium: row.get(10),
+        premium_until: row.get(11),
+        protect_content: row.get(12),
+        media_spoiler: row.get(13),
+        self_destruct: row.get(14),
+        is_active: row.get(15),
+        partner_age: partner_age(row.get(16), row.get(17)),
+        language: row.get(18),
+        strict_language: row.get(19),
+        region: row.get(20),
+        nearby: row.get(21),
+    }
+}
+
+fn partner_age(min: Option<i16>, max: Option<i16>) -> Option<(u8, u8)> {
+    min.zip(max).map(|(min, max)| (min as u8, max as u8))
+}
+
+/// PostgreSQL backend for deployments that outgrow a single SQLite file.
+pub struct PostgresStorage {
+    pool: Pool,
+}
+
+impl PostgresStorage {
+    pub async fn new(url: &str, pool_size: u32) -> Result<Self> {
+        let mut config = deadpool_postgres::Config::new();
+        config.url = Some(url.to_owned());
+        config.pool = Some(PoolConfig::new(pool_size as usize));
+
+        let pool = config.create_pool(Some(Runtime::Tokio1), NoTls)?;
+        migrations::run(&mut pool.get().await?).await?;
+
+        Ok(PostgresStorage { pool })
+    }
+
+    async fn client(&self) -> Result<Object> {
+        Ok(self.pool.get().await?)
+    }
+
+    async fn count(&self, sql: &str, gender: Option<Gender>) -> Result<usize> {
+        let client = self.client().await?;
+        let row = match gender {
+            Some(gender) => client.query_one(sql, &[&(gender as i32)]).await?,
+            None => client.query_one(sql, &[]).await?,
+        };
+
+        Ok(row.get::<_, i64>(0) as usize)
+    }
+
+    async fn execute(&self, sql: &str, params: &[&(dyn tokio_postgres::types::ToSql + Sync)]) -> Result<()> {
+        self.client().await?.execute(sql, params).await?;
+        Ok(())
+    }
+
+    async fn get_users(&self, sql: &str, limit: Option<usize>) -> Result<Vec<User>> {
+        let client = self.client().await?;
+        let rows = match limit {
+            Some(limit) => client.query(sql, &[&(limit as i64)]).await?,
+            None => client.query(sql, &[]).await?,
+        };
+
+        Ok(rows.iter().map(user_from_row).collect())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for PostgresStorage {
+    async fn get_total_users(&self) -> Result<usize> {
+        self.count("SELECT COUNT(*) FROM users", None).await
+    }
+
+    async fn get_gender_count(&self, gender: Gender) -> Result<usize> {
+        self.count("SELECT COUNT(*) FROM users WHERE gender = $1", Some(gender)).await
+    }
+
+    async fn get_total_chats(&self) -> Result<usize> {
+        self.count("SELECT COUNT(*) FROM chats", None).await
+    }
+
+    async fn get_chat(&self, user_id: i64) -> Result<Option<i64>> {
+        let row = self
+            .client().await?
+            .query_opt(
+                "SELECT chat_one, chat_two FROM chats WHERE chat_one = $1 OR chat_two = $1",
+                &[&user_id]
+            ).await?;
+
+        Ok(
+            row.map(|row| {
+                let chat_one: i64 = row.get(0);
+                let chat_two: i64 = row.get(1);
+                if chat_one == user_id {
+                    chat_two
+                } else {
+                    chat_one
+                }
+            })
+        )
+    }
+
+    async fn get_chat_type(&self, user_id: i64) -> Result<Option<ChatType>> {
+        let row = self
+            .client().await?
+            .query_opt(
+                "SELECT chat_type FROM chats WHERE chat_one = $1 OR chat_two = $1",
+                &[&user_id]
+            ).await?;
+
+        Ok(row.map(|row| ChatType::from(row.get::<_, i32>(0))))
+    }
+
+    async fn get_queue_count(&self) -> Result<usize> {
+        self.count("SELECT COUNT(*) FROM queue", None).await
+    }
+
+    async fn get_gender_queue_count(&self, gender: Gender) -> Result<usize> {
+        self.count(
+            "SELECT COUNT(*) FROM queue INNER JOIN users ON queue.user_id = users.id WHERE users.gender = $1",
+            Some(gender)
+        ).await
+    }
+
+    async fn decrease_reputation(
+        &self,
+        user_id: i64,
+        amount: i32,
+        ban_reputation: i32
+    ) -> Result<bool> {
+        let row = self
+            .client().await?
+            .query_one(
+                "UPDATE users SET reputation = reputation - $1,
+                     is_banned = is_banned OR reputation - $1 <= $2
+                 WHERE id = $3
+                 RETURNING reputation <= $2",
+                &[&amount, &ban_reputation, &user_id]
+            ).await?;
+
+        Ok(row.get(0))
+    }
+
+    async fn increase_reputation(
+        &self,
+        user_id: i64,
+        amount: i32,
+        ban_reputation: i32
+    ) -> Result<bool> {
+        let row = self
+            .client().await?
+            .query_one(
+                "UPDATE users SET reputation = reputation + $1,
+                     is_banned = CASE WHEN reputation + $1 > $2 THEN FALSE ELSE is_banned END
+                 WHERE id = $3
+                 RETURNING reputation <= $2",
+                &[&amount, &ban_reputation, &user_id]
+            ).await?;
+
+        Ok(row.get(0))
+    }
+
+    async fn ban_user(&self, user_id: i64) -> Result<()> {
+        self.execute("UPDATE users SET is_banned = TRUE WHERE id = $1", &[&user_id]).await
+    }
+
+    async fn unban_user(&self, user_id: i64) -> Result<()> {
+        self.execute("UPDATE users SET is_banned = FALSE WHERE id = $1", &[&user_id]).await
+    }
+
+    async fn add_user(&self, user: &User) -> Result<()> {
+        self.execute(
+            "INSERT INTO users (id, nickname, age, gender, language) VALUES ($1, $2, $3, $4, $5)",
+            &[&user.id, &user.nickname, &(user.age as i16), &(user.gender as i32), &user.language]
+        ).await
+    }
+
+    async fn get_user(&self, user_id: i64) -> Result<Option<User>> {
+        let row = self
+            .client().await?
+            .query_opt(&format!("SELECT {} FROM users WHERE id = $1", USER_COLUMNS), &[&user_id]).await?;
+
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<User>> {
+        self.get_users(&format!("SELECT {} FROM users", USER_COLUMNS), None).await
+    }
+
+    async fn get_top_referral_users(&self, limit: usize) -> Result<Vec<User>> {
+        self.get_users(
+            &format!("SELECT {} FROM users ORDER BY referrals DESC LIMIT $1", USER_COLUMNS),
+            Some(limit)
+        ).await
+    }
+
+    async fn get_top_reputation_users(&self, limit: usize) -> Result<Vec<User>> {
+        self.get_users(
+            &format!("SELECT {} FROM users ORDER BY reputation DESC LIMIT $1", USER_COLUMNS),
+            Some(limit)
+        ).await
+    }
+
+    async fn delete_user_by_id(&self, user_id: i64) -> Result<()> {
+        let client = self.client().await?;
+        client.execute("DELETE FROM users WHERE id = $1", &[&user_id]).await?;
+        let _ = client.execute(
+            "DELETE FROM chats WHERE chat_one = $1 OR chat_two = $1",
+            &[&user_id]
+        ).await;
+        let _ = client.execute("DELETE FROM queue WHERE user_id = $1", &[&user_id]).await;
+        let _ = client.execute("DELETE FROM user_tags WHERE user_id = $1", &[&user_id]).await;
+
+        Ok(())
+    }
+
+    async fn increase_referral_count(&self, user_id: i64) -> Result<()> {
+        self.execute("UPDATE users SET referrals = referrals + 1 WHERE id = $1", &[&user_id]).await
+    }
+
+    async fn set_user_state(&self, user_id: i64, new_state: UserState) -> Result<()> {
+        self.execute(
+            "UPDATE users SET state = $1 WHERE id = $2",
+            &[&(new_state as i32), &user_id]
+        ).await
+    }
+
+    async fn set_premium(&self, user_id: i64, is_premium: bool) -> Result<()> {
+        self.execute("UPDATE users SET is_premium = $1 WHERE id = $2", &[&is_premium, &user_id]).await
+    }
+
+    async fn update_media_settings(
+        &self,
+        user_id: i64,
+        protect_content: Option<bool>,
+        media_spoiler: Option<bool>
+    ) -> Result<()> {
+        self.execute(
+            "UPDATE users SET protect_content = $1, media_spoiler = $2 WHERE id = $3",
+            &[&protect_content, &media_spoiler, &user_id]
+        ).await
+    }
+
+    async fn set_user_active(&self, user_id: i64, is_active: bool) -> Result<()> {
+        self.execute("UPDATE users SET is_active = $1 WHERE id = $2", &[&is_active, &user_id]).await
+    }
+
+    async fn update_self_destruct(&self, user_id: i64, seconds: Option<i32>) -> Result<()> {
+        self.execute("UPDATE users SET self_destruct = $1 WHERE id = $2", &[&seconds, &user_id]).await
+    }
+
+    async fn update_partner_age(&self, user_id: i64, range: Option<(u8, u8)>) -> Result<()> {
+        self.execute(
+            "UPDATE users SET min_partner_age = $1, max_partner_age = $2 WHERE id = $3",
+            &[
+                &range.map(|(min, _)| min as i16),
+                &range.map(|(_, max)| max as i16),
+                &user_id,
+            ]
+        ).await
+    }
+
+    async fn update_language(&self, user_id: i64, language: Option<&str>, strict: bool) -> Result<()> {
+        self.execute(
+            "UPDATE users SET language = $1, strict_language = $2 WHERE id = $3",
+            &[&language, &strict, &user_id]
+        ).await
+    }
+
+    async fn update_region(&self, user_id: i64, region: Option<&str>) -> Result<()> {
+        let client = self.client().await?;
+        client.execute("UPDATE users SET region = $1 WHERE id = $2", &[&region, &user_id]).await?;
+        client.execute("UPDATE queue SET region = $1 WHERE user_id = $2", &[&region, &user_id]).await?;
+
+        Ok(())
+    }
+
+    async fn set_premium_until(&self, user_id: i64, until: i64) -> Result<()> {
+        self.execute("UPDATE users SET premium_until = $1 WHERE id = $2", &[&until, &user_id]).await
+    }
+
+    async fn update_user_nickname(&self, user_id: i64, new_nickname: &str) -> Result<()> {
+        self.execute("UPDATE users SET nickname = $1 WHERE id = $2", &[&new_nickname, &user_id]).await
+    }
+
+    async fn update_user_age(&self, user_id: i64, new_age: u8) -> Result<()> {
+        self.execute("UPDATE users SET age = $1 WHERE id = $2", &[&(new_age as i16), &user_id]).await
+    }
+
+    async fn update_user_gender(&self, user_id: i64, new_gender: Gender) -> Result<()> {
+        self.execute(
+            "UPDATE users SET gender = $1 WHERE id = $2",
+            &[&(new_gender as i32), &user_id]
+        ).await
+    }
+
+    async fn update_search_filters(
+        &self,
+        user_id: i64,
+        search_gender: SearchGender,
+        chat_type: ChatType,
+        nearby: bool
+    ) -> Result<()> {
+        self.execute(
+            "UPDATE users SET chat_type = $1, search_gender = $2, nearby = $3 WHERE id = $4",
+            &[&(chat_type as i32), &(search_gender as i32), &nearby, &user_id]
+        ).await
+    }
+
+    async fn add_to_queue(&self, searcher: &Searcher) -> Result<()> {
+        self.execute(
+            "INSERT INTO queue (user_id, search_gender, searcher_gender, chat_type, joined_at,
+                 age, min_partner_age, max_partner_age, language, strict_language, region, nearby)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+             ON CONFLICT (user_id) DO UPDATE SET search_gender = excluded.search_gender,
+                 searcher_gender = excluded.searcher_gender, chat_type = excluded.chat_type,
+                 joined_at = excluded.joined_at, age = excluded.age,
+                 min_partner_age = excluded.min_partner_age, max_partner_age = excluded.max_partner_age,
+                 language = excluded.language, strict_language = excluded.strict_language,
+                 region = excluded.region, nearby = excluded.nearby",
+            &[
+                &searcher.user_id,
+                &(searcher.search_gender as i32),
+                &(searcher.searcher_gender as i32),
+                &(searcher.chat_type.clone() as i32),
+                &searcher.joined_at,
+                &(searcher.age as i16),
+                &searcher.partner_age.map(|(min, _)| min as i16),
+                &searcher.partner_age.map(|(_, max)| max as i16),
+                &searcher.language,
+                &searcher.strict_language,
+                &searcher.region,
+                &searcher.nearby,
+            ]
+        ).await
+    }
+
+    async fn get_queue(&self) -> Result<Vec<Searcher>> {
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT user_id, search_gender, searcher_gender, chat_type, joined_at,
+                     age, min_partner_age, max_partner_age, language, strict_language, region, nearby
+                 FROM queue
+                 ORDER BY joined_at, user_id",
+                &[]
+            ).await?;
+
+        let mut searchers: Vec<Searcher> = rows
+            .iter()
+            .map(|row| Searcher {
+                user_id: row.get(0),
+                search_gender: SearchGender::from(row.get::<_, i32>(1)),
+                searcher_gender: Gender::from(row.get::<_, i32>(2)),
+                chat_type: ChatType::from(row.get::<_, i32>(3)),
+                joined_at: row.get(4),
+                age: row.get::<_, i16>(5) as u8,
+                partner_age: partner_age(row.get(6), row.get(7)),
+                tags: Vec::new(),
+                language: row.get(8),
+                strict_language: row.get(9),
+                region: row.get(10),
+                nearby: row.get(11),
+            })
+            .collect();
+
+        let tags = client.query(
+            "SELECT user_id, tag_id FROM user_tags WHERE user_id IN (SELECT user_id FROM queue)",
+            &[]
+        ).await?;
+        for row in tags {
+            let user_id: i64 = row.get(0);
+            if let Some(searcher) = searchers.iter_mut().find(|s| s.user_id == user_id) {
+                searcher.tags.push(row.get(1));
+            }
+        }
+
+        Ok(searchers)
+    }
+
+    async fn get_tags(&self) -> Result<Vec<Tag>> {
+        let rows = self.client().await?.query("SELECT id, name FROM tags ORDER BY name", &[]).await?;
+
+        Ok(
+            rows
+                .iter()
+                .map(|row| Tag { id: row.get(0), name: row.get(1) })
+                .collect()
+        )
+    }
+
+    async fn add_tag(&self, name: &str) -> Result<bool> {
+        let added = self
+            .client().await?
+            .execute("INSERT INTO tags (name) VALUES ($1) ON CONFLICT (name) DO NOTHING", &[&name]).await?;
+
+        Ok(added > 0)
+    }
+
+    async fn remove_tag(&self, name: &str) -> Result<bool> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+
+        tx.execute(
+            "DELETE FROM user_tags WHERE tag_id IN (SELECT id FROM tags WHERE name = $1)",
+            &[&name]
+        ).await?;
+        let removed = tx.execute("DELETE FROM tags WHERE name = $1", &[&name]).await?;
+        tx.commit().await?;
+
+        Ok(removed > 0)
+    }
+
+    async fn get_user_tags(&self, user_id: i64) -> Result<Vec<Tag>> {
+        let rows = self
+            .client().await?
+            .query(
+                "SELECT tags.id, tags.name FROM user_tags INNER JOIN tags ON tags.id = user_tags.tag_id
+                 WHERE user_tags.user_id = $1
+                 ORDER BY tags.name",
+                &[&user_id]
+            ).await?;
+
+        Ok(
+            rows
+                .iter()
+                .map(|row| Tag { id: row.get(0), name: row.get(1) })
+                .collect()
+        )
+    }
+
+    async fn set_user_tag(&self, user_id: i64, tag_id: i32, selected: bool) -> Result<()> {
+        if selected {
+            self.execute(
+                "INSERT INTO user_tags (user_id, tag_id) SELECT $1, id FROM tags WHERE id = $2
+                 ON CONFLICT DO NOTHING",
+                &[&user_id, &tag_id]
+            ).await
+        } else {
+            self.execute(
+                "DELETE FROM user_tags WHERE user_id = $1 AND tag_id = $2",
+                &[&user_id, &tag_id]
+            ).await
+        }
+    }
+
+    async fn add_wait_time(&self, searcher: &Searcher, matched_at: i64) -> Result<()> {
+        self.execute(
+            "INSERT INTO wait_times (user_id, gender, chat_type, waited, matched_at) VALUES ($1, $2, $3, $4, $5)",
+            &[
+                &searcher.user_id,
+                &(searcher.searcher_gender as i32),
+                &(searcher.chat_type.clone() as i32),
+                &(matched_at - searcher.joined_at).max(0),
+                &matched_at,
+            ]
+        ).await
+    }
+
+    async fn get_wait_times(&self, since: i64) -> Result<Vec<(Gender, ChatType, i64)>> {
+        let rows = self
+            .client().await?
+            .query(
+                "SELECT gender, chat_type, waited FROM wait_times WHERE matched_at >= $1",
+                &[&since]
+            ).await?;
+
+        Ok(
+            rows
+                .iter()
+                .map(|row| {
+                    (
+                        Gender::from(row.get::<_, i32>(0)),
+                        ChatType::from(row.get::<_, i32>(1)),
+                        row.get(2),
+                    )
+                })
+                .collect()
+        )
+    }
+
+    async fn dequeue_user(&self, user_id: i64) -> Result<()> {
+        self.execute("DELETE FROM queue WHERE user_id = $1", &[&user_id]).await
+    }
+
+    async fn delete_chat(&self, user_id: i64) -> Result<Option<i64>> {
+        let client = self.client().await?;
+        let row = client.query_opt(
+            "DELETE FROM chats WHERE chat_one = $1 OR chat_two = $1 RETURNING chat_one, chat_two",
+            &[&user_id]
+        ).await?;
+
+        let interlocutor_id = row.map(|row| {
+            let chat_one: i64 = row.get(0);
+            let chat_two: i64 = row.get(1);
+            if chat_one == user_id {
+                chat_two
+            } else {
+                chat_one
+            }
+        });
+
+        if let Some(interlocutor_id) = interlocutor_id {
+            client.execute(
+                "DELETE FROM message_links
+                 WHERE (sender_chat = $1 AND receiver_chat = $2) OR (sender_chat = $2 AND receiver_chat = $1)",
+                &[&user_id, &interlocutor_id]
+            ).await?;
+        }
+
+        Ok(interlocutor_id)
+    }
+
+    async fn create_chat(&self, user_id: i64, partner_id: i64, chat_type: ChatType) -> Result<bool> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+
+        // Locking both user rows serialises this with bans and deletions.
+        let users = tx.query(
+            "SELECT id FROM users WHERE id IN ($1, $2) AND NOT is_banned FOR UPDATE",
+            &[&user_id, &partner_id]
+        ).await?;
+        if users.len() != 2 {
+            return Ok(false);
+        }
+
+        let in_chat = tx.query_opt(
+            "SELECT 1 FROM chats WHERE chat_one IN ($1, $2) OR chat_two IN ($1, $2)",
+            &[&user_id, &partner_id]
+        ).await?;
+        if in_chat.is_some() {
+            return Ok(false);
+        }
+
+        if tx.execute("DELETE FROM queue WHERE user_id = $1", &[&partner_id]).await? == 0 {
+            return Ok(false);
+        }
+        tx.execute("DELETE FROM queue WHERE user_id = $1", &[&user_id]).await?;
+        tx.execute(
+            "INSERT INTO chats (chat_one, chat_two, chat_type) VALUES ($1, $2, $3)",
+            &[&user_id, &partner_id, &(chat_type as i32)]
+        ).await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn delete_chat_by_id(&self, chat_id: i64) -> Result<()> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+
+        let members = tx.query_opt(
+            "DELETE FROM chats WHERE id = $1 RETURNING chat_one, chat_two",
+            &[&chat_id]
+        ).await?;
+
+        // A duplicate row for the same pair may survive; its rows are shared.
+        if let Some(row) = members {
+            let chat_one: i64 = row.get(0);
+            let chat_two: i64 = row.get(1);
+
+            for table in ["message_links", "self_destructs"] {
+                tx.execute(
+                    &format!(
+                        "DELETE FROM {}
+                         WHERE ((sender_chat = $1 AND receiver_chat = $2) OR (sender_chat = $2 AND receiver_chat = $1))
+                           AND NOT EXISTS (
+                               SELECT 1 FROM chats
+                               WHERE (chat_one = $1 AND chat_two = $2) OR (chat_one = $2 AND chat_two = $1)
+                           )",
+                        table
+                    ),
+                    &[&chat_one, &chat_two]
+                ).await?;
+            }
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_chats(&self) -> Result<Vec<(i64, i64, i64)>> {
+        let rows = self
+            .client().await?
+            .query("SELECT id, chat_one, chat_two FROM chats ORDER BY id", &[]).await?;
+
+        Ok(
+            rows
+                .iter()
+                .map(|row| (row.get(0), row.get(1), row.get(2)))
+                .collect()
+        )
+    }
+
+    async fn add_message_link(
+        &self,
+        sender_chat: i64,
+        sender_msg: i32,
+        receiver_chat: i64,
+        receiver_msg: i32
+    ) -> Result<()> {
+        self.execute(
+            "INSERT INTO message_links (sender_chat, sender_msg, receiver_chat, receiver_msg)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT (sender_chat, sender_msg) DO UPDATE SET receiver_chat = excluded.receiver_chat,
+                 receiver_msg = excluded.receiver_msg",
+            &[&sender_chat, &sender_msg, &receiver_chat, &receiver_msg]
+        ).await
+    }
+
+    async fn get_linked_message(&self, chat_id: i64, msg_id: i32) -> Result<Option<(i64, i32)>> {
+        let row = self
+            .client().await?
+            .query_opt(
+                "SELECT receiver_chat, receiver_msg FROM message_links WHERE sender_chat = $1 AND sender_msg = $2
+                 UNION ALL
+                 SELECT sender_chat, sender_msg FROM message_links WHERE receiver_chat = $1 AND receiver_msg = $2
+                 LIMIT 1",
+                &[&chat_id, &msg_id]
+            ).await?;
+
+        Ok(row.map(|row| (row.get(0), row.get(1))))
+    }
+
+    async fn get_relayed_copy(&self, sender_chat: i64, sender_msg: i32) -> Result<Option<(i64, i32)>> {
+        let row = self
+            .client().await?
+            .query_opt(
+                "SELECT receiver_chat, receiver_msg FROM message_links WHERE sender_chat = $1 AND sender_msg = $2",
+                &[&sender_chat, &sender_msg]
+            ).await?;
+
+        Ok(row.map(|row| (row.get(0), row.get(1))))
+    }
+
+    async fn get_last_relayed_message(&self, sender_chat: i64) -> Result<Option<(i32, i64, i32)>> {
+        let row = self
+            .client().await?
+            .query_opt(
+                "SELECT sender_msg, receiver_chat, receiver_msg FROM message_links
+                 WHERE sender_chat = $1 ORDER BY sender_msg DESC LIMIT 1",
+                &[&sender_chat]
+            ).await?;
+
+        Ok(row.map(|row| (row.get(0), row.get(1), row.get(2))))
+    }
+
+    async fn delete_message_link(&self, sender_chat: i64, sender_msg: i32) -> Result<()> {
+        self.execute(
+            "DELETE FROM message_links WHERE sender_chat = $1 AND sender_msg = $2",
+            &[&sender_chat, &sender_msg]
+        ).await
+    }
+
+    async fn add_self_destruct(
+        &self,
+        receiver_chat: i64,
+        receiver_msg: i32,
+        sender_chat: i64,
+        delete_at: Option<i64>,
+        anchor: Option<i32>
+    ) -> Result<()> {
+        self.execute(
+            "INSERT INTO self_destructs (receiver_chat, receiver_msg, sender_chat, delete_at, anchor)
+             VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT (receiver_chat, receiver_msg)
+             DO UPDATE SET sender_chat = $3, delete_at = $4, anchor = $5",
+            &[&receiver_chat, &receiver_msg, &sender_chat, &delete_at, &anchor]
+        ).await
+    }
+
+    async fn get_self_destruct_timers(&self) -> Result<Vec<(i64, i32, i64)>> {
+        let rows = self
+            .client().await?
+            .query(
+                "SELECT receiver_chat, receiver_msg, delete_at FROM self_destructs WHERE delete_at IS NOT NULL",
+                &[]
+            ).await?;
+
+        Ok(
+            rows
+                .iter()
+                .map(|row| (row.get(0), row.get(1), row.get(2)))
+                .collect()
+        )
+    }
+
+    async fn remove_self_destruct(&self, receiver_chat: i64, receiver_msg: i32) -> Result<bool> {
+        let removed = self
+            .client().await?
+            .execute(
+                "DELETE FROM self_destructs WHERE receiver_chat = $1 AND receiver_msg = $2",
+                &[&receiver_chat, &receiver_msg]
+            ).await?;
+
+        Ok(removed > 0)
+    }
+
+    async fn get_view_once_anchor(&self, receiver_chat: i64, receiver_msg: i32) -> Result<Option<i32>> {
+        let row = self
+            .client().await?
+            .query_opt(
+                "SELECT anchor FROM self_destructs
+                 WHERE receiver_chat = $1 AND receiver_msg = $2 AND anchor IS NOT NULL",
+                &[&receiver_chat, &receiver_msg]
+            ).await?;
+
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    async fn take_view_once(&self, receiver_chat: i64, anchor: i32) -> Result<Vec<i32>> {
+        let rows = self
+            .client().await?
+            .query(
+                "DELETE FROM self_destructs WHERE receiver_chat = $1 AND anchor = $2 RETURNING receiver_msg",
+                &[&receiver_chat, &anchor]
+            ).await?;
+
+        Ok(
+            rows
+                .iter()
+                .map(|row| row.get(0))
+                .collect()
+        )
+    }
+
+    async fn take_dialog_self_destructs(&self, user_id: i64, partner_id: i64) -> Result<Vec<(i64, i32)>> {
+        let rows = self
+            .client().await?
+            .query(
+                "DELETE FROM self_destructs
+                 WHERE (sender_chat = $1 AND receiver_chat = $2) OR (sender_chat = $2 AND receiver_chat = $1)
+                 RETURNING receiver_chat, receiver_msg",
+                &[&user_id, &partner_id]
+            ).await?;
+
+        Ok(
+            rows
+                .iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect()
+        )
+    }
+
+    async fn get_dialogue_states(&self) -> Result<Vec<(i64, String)>> {
+        let rows = self.client().await?.query("SELECT chat_id, state FROM dialogues", &[]).await?;
+
+        Ok(
+            rows
+                .iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect()
+        )
+    }
+
+    async fn get_dialogue_state(&self, chat_id: i64) -> Result<Option<String>> {
+        let row = self
+            .client().await?
+            .query_opt("SELECT state FROM dialogues WHERE chat_id = $1", &[&chat_id]).await?;
+
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    async fn set_dialogue_state(&self, chat_id: i64, state: String) -> Result<()> {
+        self.execute(
+            "INSERT INTO dialogues (chat_id, state) VALUES ($1, $2)
+             ON CONFLICT (chat_id) DO UPDATE SET state = EXCLUDED.state",
+            &[&chat_id, &state]
+        ).await
+    }
+
+    async fn remove_dialogue_state(&self, chat_id: i64) -> Result<()> {
+        self.execute("DELETE FROM dialogues WHERE chat_id = $1", &[&chat_id]).await
+    }
+
+    async fn add_restart_notice(&self, user_id: i64) -> Result<()> {
+        self.execute(
+            "INSERT INTO restart_notices (user_id) VALUES ($1) ON CONFLICT DO NOTHING",
+            &[&user_id]
+        ).await
+    }
+
+    async fn get_restart_notices(&self) -> Result<Vec<i64>> {
+        let rows = self.client().await?.query("SELECT user_id FROM restart_notices", &[]).await?;
+
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    async fn clear_restart_notices(&self) -> Result<()> {
+        self.execute("DELETE FROM restart_notices", &[]).await
+    }
+}
@@ -0,0 +1,265 @@
+use rusqlite::{ params, Connection };
+
+use crate::database::{ Error, Migration, Result };
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        sql: "CREATE TABLE IF NOT EXISTS users (
+                  id INTEGER PRIMARY KEY,
+                  is_premium BOOLEAN DEFAULT 0,
+                  premium_until INTEGER DEFAULT 0,
+                  nickname TEXT NOT NULL,
+                  age INTEGER NOT NULL,
+                  gender TEXT NOT NULL,
+                  search_gender TEXT DEFAULT Male,
+                  chat_type INTEGER DEFAULT 0,
+                  state INTEGER DEFAULT 0,
+                  reputation INTEGER DEFAULT 0,
+                  is_banned BOOLEAN DEFAULT 0,
+                  referrals INTEGER DEFAULT 0
+              );
+
+              CREATE TABLE IF NOT EXISTS queue (
+                  user_id INTEGER PRIMARY KEY,
+                  search_gender INTEGER DEFAULT 0,
+                  searcher_gender INTEGER NOT NULL,
+                  chat_type INTEGER DEFAULT 0,
+                  UNIQUE(user_id)
+              );
+
+              CREATE TABLE IF NOT EXISTS chats (
+                  id INTEGER PRIMARY KEY,
+                  chat_one INTEGER KEY NOT NULL,
+                  chat_two INTEGER KEY NOT NULL,
+                  chat_type INTEGER DEFAULT 0,
+                  UNIQUE(chat_one),
+                  UNIQUE(chat_two)
+              );
+
+              CREATE TABLE IF NOT EXISTS dialogues (
+                  chat_id INTEGER PRIMARY KEY,
+                  state TEXT NOT NULL
+              );",
+    },
+    Migration {
+        version: 2,
+        description: "store genders as integers and tighten column types",
+        sql: "CREATE TABLE users_new (
+                  id INTEGER PRIMARY KEY,
+                  nickname TEXT NOT NULL,
+                  age INTEGER NOT NULL,
+                  gender INTEGER NOT NULL,
+                  search_gender INTEGER NOT NULL DEFAULT 0,
+                  chat_type INTEGER NOT NULL DEFAULT 0,
+                  state INTEGER NOT NULL DEFAULT 0,
+                  reputation INTEGER NOT NULL DEFAULT 0,
+                  is_banned BOOLEAN NOT NULL DEFAULT 0,
+                  referrals INTEGER NOT NULL DEFAULT 0,
+                  is_premium BOOLEAN NOT NULL DEFAULT 0,
+                  premium_until INTEGER NOT NULL DEFAULT 0
+              );
+
+              INSERT INTO users_new (
+                  id, nickname, age, gender, search_gender, chat_type, state,
+                  reputation, is_banned, referrals, is_premium, premium_until
+              )
+              SELECT
+                  id,
+                  nickname,
+                  age,
+                  CASE WHEN lower(gender) = 'female' THEN 1 ELSE 0 END,
+                  CASE WHEN lower(search_gender) = 'female' THEN 1 ELSE 0 END,
+                  COALESCE(chat_type, 0),
+                  COALESCE(state, 0),
+                  COALESCE(reputation, 0),
+                  COALESCE(is_banned, 0),
+                  COALESCE(referrals, 0),
+                  COALESCE(is_premium, 0),
+                  COALESCE(premium_until, 0)
+              FROM users;
+
+              DROP TABLE users;
+              ALTER TABLE users_new RENAME TO users;
+
+              CREATE TABLE queue_new (
+                  user_id INTEGER PRIMARY KEY,
+                  search_gender INTEGER NOT NULL DEFAULT 0,
+                  searcher_gender INTEGER NOT NULL,
+                  chat_type INTEGER NOT NULL DEFAULT 0
+              );
+
+              INSERT INTO queue_new (user_id, search_gender, searcher_gender, chat_type)
+              SELECT user_id, COALESCE(search_gender, 0), searcher_gender, COALESCE(chat_type, 0)
+              FROM queue;
+
+              DROP TABLE queue;
+              ALTER TABLE queue_new RENAME TO queue;
+
+              CREATE TABLE chats_new (
+                  id INTEGER PRIMARY KEY,
+                  chat_one INTEGER NOT NULL UNIQUE,
+                  chat_two INTEGER NOT NULL UNIQUE,
+                  chat_type INTEGER NOT NULL DEFAULT 0
+              );
+
+              INSERT INTO chats_new (id, chat_one, chat_two, chat_type)
+              SELECT id, chat_one, chat_two, COALESCE(chat_type, 0)
+              FROM chats;
+
+              DROP TABLE chats;
+              ALTER TABLE chats_new RENAME TO chats;",
+    },
+    Migration {
+        version: 3,
+        description: "remember who to notify after a restart",
+        sql: "CREATE TABLE restart_notices (
+                  user_id INTEGER PRIMARY KEY
+              );",
+    },
+    Migration {
+        version: 4,
+        description: "record queue join times and match wait times",
+        sql: "ALTER TABLE queue ADD COLUMN joined_at INTEGER NOT NULL DEFAULT 0;
+
+              CREATE TABLE wait_times (
+                  id INTEGER PRIMARY KEY,
+                  user_id INTEGER NOT NULL,
+                  gender INTEGER NOT NULL,
+                  chat_type INTEGER NOT NULL,
+                  waited INTEGER NOT NULL,
+                  matched_at INTEGER NOT NULL
+              );
+
+              CREATE INDEX wait_times_matched_at ON wait_times (matched_at);",
+    },
+    Migration {
+        version: 5,
+        description: "map relayed messages between the two sides of a chat",
+        sql: "CREATE TABLE message_links (
+                  sender_chat INTEGER NOT NULL,
+                  sender_msg INTEGER NOT NULL,
+                  receiver_chat INTEGER NOT NULL,
+                  receiver_msg INTEGER NOT NULL,
+                  PRIMARY KEY (sender_chat, sender_msg)
+              );
+
+              CREATE INDEX message_links_receiver ON message_links (receiver_chat, receiver_msg);",
+    },
+    Migration {
+        version: 6,
+        description: "let users override how relayed media is delivered to them",
+        sql: "ALTER TABLE users ADD COLUMN protect_content INTEGER;
+              ALTER TABLE users ADD COLUMN media_spoiler INTEGER;",
+    },
+    Migration {
+        version: 7,
+        description: "self-destructing media",
+        sql: "ALTER TABLE users ADD COLUMN self_destruct INTEGER;
+
+              CREATE TABLE self_destructs (
+                  receiver_chat INTEGER NOT NULL,
+                  receiver_msg INTEGER NOT NULL,
+                  sender_chat INTEGER NOT NULL,
+                  delete_at INTEGER,
+                  anchor INTEGER,
+                  PRIMARY KEY (receiver_chat, receiver_msg)
+              );",
+    },
+    Migration {
+        version: 8,
+        description: "mark users who blocked the bot or deleted their account",
+        sql: "ALTER TABLE users ADD COLUMN is_active INTEGER NOT NULL DEFAULT 1;",
+    },
+    Migration {
+        version: 9,
+        description: "partner age ranges",
+        sql: "ALTER TABLE users ADD COLUMN min_partner_age INTEGER;
+              ALTER TABLE users ADD COLUMN max_partner_age INTEGER;
+
+              ALTER TABLE queue ADD COLUMN age INTEGER NOT NULL DEFAULT 0;
+              ALTER TABLE queue ADD COLUMN min_partner_age INTEGER;
+              ALTER TABLE queue ADD COLUMN max_partner_age INTEGER;
+              UPDATE queue SET age = (SELECT age FROM users WHERE users.id = queue.user_id)
+              WHERE EXISTS (SELECT 1 FROM users WHERE users.id = queue.user_id);",
+    },
+    Migration {
+        version: 10,
+        description: "interest tags",
+        sql: "CREATE TABLE tags (
+                  id INTEGER PRIMARY KEY AUTOINCREMENT,
+                  name TEXT NOT NULL UNIQUE
+              );
+
+              CREATE TABLE user_tags (
+                  user_id INTEGER NOT NULL,
+                  tag_id INTEGER NOT NULL,
+                  PRIMARY KEY (user_id, tag_id)
+              );
+              CREATE INDEX user_tags_tag ON user_tags (tag_id);",
+    },
+    Migration {
+        version: 11,
+        description: "user languages",
+        sql: "ALTER TABLE users ADD COLUMN language TEXT;
+              ALTER TABLE users ADD COLUMN strict_language INTEGER NOT NULL DEFAULT 0;
+
+              ALTER TABLE queue ADD COLUMN language TEXT;
+              ALTER TABLE queue ADD COLUMN strict_language INTEGER NOT NULL DEFAULT 0;",
+    },
+    Migration {
+        version: 12,
+        description: "coarse user regions for nearby search",
+        sql: "ALTER TABLE users ADD COLUMN region TEXT;
+              ALTER TABLE users ADD COLUMN nearby INTEGER NOT NULL DEFAULT 0;
+
+              ALTER TABLE queue ADD COLUMN region TEXT;
+              ALTER TABLE queue ADD COLUMN nearby INTEGER NOT NULL DEFAULT 0;",
+    },
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn current_version(connection: &Connection) -> rusqlite::Result<u32> {
+    connection.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row|
+        row.get(0)
+    )
+}
+
+/// Brings the schema up to `latest_version()`, applying each pending
+/// migration in its own transaction. Refuses to touch a database that was
+/// migrated by a newer build.
+pub fn run(connection: &mut Connection) -> Result<()> {
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+              version INTEGER PRIMARY KEY,
+              description TEXT NOT NULL,
+              applied_at INTEGER NOT NULL
+              )",
+        []
+    )?;
+
+    let current = current_version(connection)?;
+    let supported = latest_version();
+
+    if current > supported {
+        return Err(Error::SchemaTooNew { found: current, supported });
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        log::info!("Applying migration {}: {}", migration.version, migration.description);
+
+        let tx = connection.transaction()?;
+        tx.execute_batch(migration.sql)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+            params![migration.version, migration.description, chrono::Utc::now().timestamp()]
+        )?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
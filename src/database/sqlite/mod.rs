@@ -0,0 +1,888 @@
+pub mod migrations;
+pub mod pool;
+
+use async_trait::async_trait;
+use pool::SqliteConnectionManager;
+use r2d2::Pool;
+use rusqlite::{ params, Connection, OptionalExtension, Row, TransactionBehavior };
+
+use super::{ Result, StorageBackend };
+use crate::{
+    models::{
+        chat_type::ChatType,
+        gender::Gender,
+        search_gender::SearchGender,
+        searcher::Searcher,
+        tag::Tag,
+        user::User,
+    },
+    user_state::UserState,
+};
+
+const USER_COLUMNS: &str =
+    "id, nickname, age, gender, state, reputation, is_banned, search_gender, chat_type, referrals, is_premium, premium_until, protect_content, media_spoiler, self_destruct, is_active, min_partner_age, max_partner_age, language, strict_language, region, nearby";
+
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    let gender: i32 = row.get(3)?;
+    let state: i32 = row.get(4)?;
+    let search_gender: i32 = row.get(7)?;
+    let chat_type: i32 = row.get(8)?;
+
+    Ok(User {
+        id: row.get(0)?,
+        nickname: row.get(1)?,
+        age: row.get(2)?,
+        gender: Gender::from(gender),
+        state: UserState::from(state),
+        reputation: row.get(5)?,
+        is_banned: row.get(6)?,
+        search_gender: Some(SearchGender::from(search_gender)),
+        chat_type: Some(ChatType::from(chat_type)),
+        referrals: row.get(9)?,
+        is_premium: row.get(10)?,
+        premium_until: row.get(11)?,
+        protect_content: row.get(12)?,
+        media_spoiler: row.get(13)?,
+        self_destruct: row.get(14)?,
+        is_active: row.get(15)?,
+        partner_age: partner_age(row.get(16)?, row.get(17)?),
+        language: row.get(18)?,
+        strict_language: row.get(19)?,
+        region: row.get(20)?,
+        nearby: row.get(21)?,
+    })
+}
+
+fn partner_age(min: Option<u8>, max: Option<u8>) -> Option<(u8, u8)> {
+    min.zip(max)
+}
+
+fn get_users(conn: &Connection, sql: &str, limit: Option<usize>) -> rusqlite::Result<Vec<User>> {
+    let mut stmt = conn.prepare(sql)?;
+    let user_iter = match limit {
+        Some(limit) => stmt.query_map(params![limit as i64], user_from_row)?,
+        None => stmt.query_map([], user_from_row)?,
+    };
+
+    user_iter.collect()
+}
+
+fn delete_chat(conn: &Connection, user_id: i64) -> rusqlite::Result<Option<i64>> {
+    // A single statement, so two users ending the same chat at once can't
+    // both get the interlocutor back.
+    let interlocutor_id = conn
+        .query_row(
+            "DELETE FROM chats WHERE chat_one = ?1 OR chat_two = ?1 RETURNING chat_one, chat_two",
+            params![user_id],
+            |row| {
+                let chat_one: i64 = row.get(0)?;
+                let chat_two: i64 = row.get(1)?;
+
+                if chat_one == user_id {
+                    Ok(chat_two)
+                } else {
+                    Ok(chat_one)
+                }
+            }
+        )
+        .optional()?;
+
+    if let Some(interlocutor_id) = interlocutor_id {
+        conn.execute(
+            "DELETE FROM message_links
+             WHERE (sender_chat = ?1 AND receiver_chat = ?2) OR (sender_chat = ?2 AND receiver_chat = ?1)",
+            params![user_id, interlocutor_id]
+        )?;
+    }
+
+    Ok(interlocutor_id)
+}
+
+/// SQLite backend. Connections come from a WAL-mode pool, and every query
+/// runs on tokio's blocking thread pool so a slow statement never stalls the
+/// async runtime.
+pub struct SqliteStorage {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl SqliteStorage {
+    pub fn new(db_path: &str, pool_size: u32) -> Result<Self> {
+        let pool = Pool::builder()
+            .max_size(pool_size)
+            .build(SqliteConnectionManager::new(db_path))?;
+        migrations::run(&mut *pool.get()?)?;
+
+        Ok(SqliteStorage { pool })
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T>
+        where T: Send + 'static, F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static
+    {
+        let pool = self.pool.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut conn = pool.get()?;
+            Ok(f(&mut conn)?)
+        }).await?
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SqliteStorage {
+    async fn get_total_users(&self) -> Result<usize> {
+        self.run(|conn| conn.query_row("SELECT COUNT(*) FROM users", [], |row| row.get(0))).await
+    }
+
+    async fn get_gender_count(&self, gender: Gender) -> Result<usize> {
+        self.run(move |conn| {
+            conn.query_row("SELECT COUNT(*) FROM users WHERE gender = ?1", params![gender as i32], |row|
+                row.get(0)
+            )
+        }).await
+    }
+
+    async fn get_total_chats(&self) -> Result<usize> {
+        self.run(|conn| conn.query_row("SELECT COUNT(*) FROM chats", [], |row| row.get(0))).await
+    }
+
+    async fn get_chat(&self, user_id: i64) -> Result<Option<i64>> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT chat_one, chat_two FROM chats WHERE chat_one = ?1 OR chat_two = ?1",
+                params![user_id],
+                |row| {
+                    let chat_one: i64 = row.get(0)?;
+                    let chat_two: i64 = row.get(1)?;
+                    if chat_one == user_id {
+                        Ok(chat_two)
+                    } else {
+                        Ok(chat_one)
+                    }
+                }
+            ).optional()
+        }).await
+    }
+
+    async fn get_chat_type(&self, user_id: i64) -> Result<Option<ChatType>> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT chat_type FROM chats WHERE chat_one = ?1 OR chat_two = ?1",
+                params![user_id],
+                |row| Ok(ChatType::from(row.get::<_, i32>(0)?))
+            ).optional()
+        }).await
+    }
+
+    async fn get_queue_count(&self) -> Result<usize> {
+        self.run(|conn| conn.query_row("SELECT COUNT(*) FROM queue", [], |row| row.get(0))).await
+    }
+
+    async fn get_gender_queue_count(&self, gender: Gender) -> Result<usize> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT COUNT(*) FROM queue INNER JOIN users ON queue.user_id = users.id WHERE users.gender = ?1",
+                params![gender as i32],
+                |row| row.get(0)
+            )
+        }).await
+    }
+
+    async fn decrease_reputation(
+        &self,
+        user_id: i64,
+        amount: i32,
+        ban_reputation: i32
+    ) -> Result<bool> {
+        // One statement, so concurrent votes can't lose an update.
+        self.run(move |conn| {
+            conn.query_row(
+                "UPDATE users SET reputation = reputation - ?1,
+                     is_banned = is_banned OR reputation - ?1 <= ?2
+                 WHERE id = ?3
+                 RETURNING reputation <= ?2",
+                params![amount, ban_reputation, user_id],
+                |row| row.get(0)
+            )
+        }).await
+    }
+
+    async fn ban_user(&self, user_id: i64) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute("UPDATE users SET is_banned = ?1 WHERE id = ?2", params![true, user_id])?;
+            Ok(())
+        }).await
+    }
+
+    async fn unban_user(&self, user_id: i64) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute("UPDATE users SET is_banned = ?1 WHERE id = ?2", params![false, user_id])?;
+            Ok(())
+        }).await
+    }
+
+    async fn increase_reputation(
+        &self,
+        user_id: i64,
+        amount: i32,
+        ban_reputation: i32
+    ) -> Result<bool> {
+        self.run(move |conn| {
+            conn.query_row(
+                "UPDATE users SET reputation = reputation + ?1,
+                     is_banned = CASE WHEN reputation + ?1 > ?2 THEN 0 ELSE is_banned END
+                 WHERE id = ?3
+                 RETURNING reputation <= ?2",
+                params![amount, ban_reputation, user_id],
+                |row| row.get(0)
+            )
+        }).await
+    }
+
+    async fn add_user(&self, user: &User) -> Result<()> {
+        let (id, nickname, age, gender) = (user.id, user.nickname.clone(), user.age, user.gender);
+        let language = user.language.clone();
+
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO users (id, nickname, age, gender, language) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![id, nickname, age, gender as i32, language]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn delete_chat(&self, user_id: i64) -> Result<Option<i64>> {
+        self.run(move |conn| delete_chat(conn, user_id)).await
+    }
+
+    async fn get_user(&self, user_id: i64) -> Result<Option<User>> {
+        self.run(move |conn| {
+            conn.query_row(
+                &format!("SELECT {} FROM users WHERE id = ?1", USER_COLUMNS),
+                params![user_id],
+                user_from_row
+            ).optional()
+        }).await
+    }
+
+    async fn increase_referral_count(&self, user_id: i64) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "UPDATE users SET referrals = referrals + 1 WHERE id = ?1",
+                params![user_id]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_top_referral_users(&self, limit: usize) -> Result<Vec<User>> {
+        self.run(move |conn| {
+            get_users(
+                conn,
+                &format!("SELECT {} FROM users ORDER BY referrals DESC LIMIT ?1", USER_COLUMNS),
+                Some(limit)
+            )
+        }).await
+    }
+
+    async fn delete_user_by_id(&self, user_id: i64) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute("DELETE FROM users WHERE id = ?1", params![user_id])?;
+            let _ = delete_chat(conn, user_id);
+            let _ = conn.execute("DELETE FROM queue WHERE user_id = ?1", params![user_id]);
+            let _ = conn.execute("DELETE FROM user_tags WHERE user_id = ?1", params![user_id]);
+
+            Ok(())
+        }).await
+    }
+
+    async fn get_top_reputation_users(&self, limit: usize) -> Result<Vec<User>> {
+        self.run(move |conn| {
+            get_users(
+                conn,
+                &format!("SELECT {} FROM users ORDER BY reputation DESC LIMIT ?1", USER_COLUMNS),
+                Some(limit)
+            )
+        }).await
+    }
+
+    async fn get_all_users(&self) -> Result<Vec<User>> {
+        self.run(|conn| get_users(conn, &format!("SELECT {} FROM users", USER_COLUMNS), None)).await
+    }
+
+    async fn set_user_state(&self, user_id: i64, new_state: UserState) -> Result<()> {
+        let state_int = new_state as i32;
+        self.run(move |conn| {
+            conn.execute("UPDATE users SET state = ?1 WHERE id = ?2", params![state_int, user_id])?;
+            Ok(())
+        }).await
+    }
+
+    async fn set_premium_until(&self, user_id: i64, until: i64) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "UPDATE users SET premium_until = ?1 WHERE id = ?2",
+                params![until, user_id]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn update_media_settings(
+        &self,
+        user_id: i64,
+        protect_content: Option<bool>,
+        media_spoiler: Option<bool>
+    ) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "UPDATE users SET protect_content = ?1, media_spoiler = ?2 WHERE id = ?3",
+                params![protect_content, media_spoiler, user_id]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn set_user_active(&self, user_id: i64, is_active: bool) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "UPDATE users SET is_active = ?1 WHERE id = ?2",
+                params![is_active, user_id]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn update_self_destruct(&self, user_id: i64, seconds: Option<i32>) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "UPDATE users SET self_destruct = ?1 WHERE id = ?2",
+                params![seconds, user_id]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn update_partner_age(&self, user_id: i64, range: Option<(u8, u8)>) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "UPDATE users SET min_partner_age = ?1, max_partner_age = ?2 WHERE id = ?3",
+                params![range.map(|(min, _)| min), range.map(|(_, max)| max), user_id]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn update_language(&self, user_id: i64, language: Option<&str>, strict: bool) -> Result<()> {
+        let language = language.map(str::to_owned);
+        self.run(move |conn| {
+            conn.execute(
+                "UPDATE users SET language = ?1, strict_language = ?2 WHERE id = ?3",
+                params![language, strict, user_id]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn update_region(&self, user_id: i64, region: Option<&str>) -> Result<()> {
+        let region = region.map(str::to_owned);
+        self.run(move |conn| {
+            conn.execute("UPDATE users SET region = ?1 WHERE id = ?2", params![region, user_id])?;
+            conn.execute("UPDATE queue SET region = ?1 WHERE user_id = ?2", params![region, user_id])?;
+            Ok(())
+        }).await
+    }
+
+    async fn set_premium(&self, user_id: i64, is_premium: bool) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "UPDATE users SET is_premium = ?1 WHERE id = ?2",
+                params![is_premium, user_id]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn update_search_filters(
+        &self,
+        user_id: i64,
+        search_gender: SearchGender,
+        chat_type: ChatType,
+        nearby: bool
+    ) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "UPDATE users SET chat_type = ?1, search_gender = ?2, nearby = ?3 WHERE id = ?4",
+                params![chat_type as i32, search_gender as i32, nearby, user_id]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn add_to_queue(&self, searcher: &Searcher) -> Result<()> {
+        let searcher = searcher.clone();
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO queue (user_id, search_gender, searcher_gender, chat_type, joined_at,
+                     age, min_partner_age, max_partner_age, language, strict_language, region, nearby)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    searcher.user_id,
+                    searcher.search_gender as i32,
+                    searcher.searcher_gender as i32,
+                    searcher.chat_type as i32,
+                    searcher.joined_at,
+                    searcher.age,
+                    searcher.partner_age.map(|(min, _)| min),
+                    searcher.partner_age.map(|(_, max)| max),
+                    searcher.language,
+                    searcher.strict_language,
+                    searcher.region,
+                    searcher.nearby
+                ]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_queue(&self) -> Result<Vec<Searcher>> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT user_id, search_gender, searcher_gender, chat_type, joined_at,
+                     age, min_partner_age, max_partner_age, language, strict_language, region, nearby
+                 FROM queue
+                 ORDER BY joined_at, rowid"
+            )?;
+            let searchers = stmt.query_map([], |row| {
+                Ok(Searcher {
+                    user_id: row.get(0)?,
+                    search_gender: SearchGender::from(row.get::<_, i32>(1)?),
+                    searcher_gender: Gender::from(row.get::<_, i32>(2)?),
+                    chat_type: ChatType::from(row.get::<_, i32>(3)?),
+                    joined_at: row.get(4)?,
+                    age: row.get(5)?,
+                    partner_age: partner_age(row.get(6)?, row.get(7)?),
+                    tags: Vec::new(),
+                    language: row.get(8)?,
+                    strict_language: row.get(9)?,
+                    region: row.get(10)?,
+                    nearby: row.get(11)?,
+                })
+            })?;
+            let mut searchers = searchers.collect::<rusqlite::Result<Vec<Searcher>>>()?;
+
+            let mut stmt = conn.prepare(
+                "SELECT user_id, tag_id FROM user_tags WHERE user_id IN (SELECT user_id FROM queue)"
+            )?;
+            let tags = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i32>(1)?)))?;
+            for tag in tags {
+                let (user_id, tag_id) = tag?;
+                if let Some(searcher) = searchers.iter_mut().find(|s| s.user_id == user_id) {
+                    searcher.tags.push(tag_id);
+                }
+            }
+
+            Ok(searchers)
+        }).await
+    }
+
+    async fn get_tags(&self) -> Result<Vec<Tag>> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare("SELECT id, name FROM tags ORDER BY name")?;
+            let tags = stmt.query_map([], |row| Ok(Tag { id: row.get(0)?, name: row.get(1)? }))?;
+            tags.collect()
+        }).await
+    }
+
+    async fn add_tag(&self, name: &str) -> Result<bool> {
+        let name = name.to_owned();
+        self.run(move |conn| {
+            let added = conn.execute("INSERT OR IGNORE INTO tags (name) VALUES (?1)", params![name])?;
+            Ok(added > 0)
+        }).await
+    }
+
+    async fn remove_tag(&self, name: &str) -> Result<bool> {
+        let name = name.to_owned();
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "DELETE FROM user_tags WHERE tag_id IN (SELECT id FROM tags WHERE name = ?1)",
+                params![name]
+            )?;
+            let removed = tx.execute("DELETE FROM tags WHERE name = ?1", params![name])?;
+            tx.commit()?;
+
+            Ok(removed > 0)
+        }).await
+    }
+
+    async fn get_user_tags(&self, user_id: i64) -> Result<Vec<Tag>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT tags.id, tags.name FROM user_tags INNER JOIN tags ON tags.id = user_tags.tag_id
+                 WHERE user_tags.user_id = ?1
+                 ORDER BY tags.name"
+            )?;
+            let tags = stmt.query_map(params![user_id], |row| {
+                Ok(Tag { id: row.get(0)?, name: row.get(1)? })
+            })?;
+            tags.collect()
+        }).await
+    }
+
+    async fn set_user_tag(&self, user_id: i64, tag_id: i32, selected: bool) -> Result<()> {
+        self.run(move |conn| {
+            if selected {
+                conn.execute(
+                    "INSERT OR IGNORE INTO user_tags (user_id, tag_id) SELECT ?1, id FROM tags WHERE id = ?2",
+                    params![user_id, tag_id]
+                )?;
+            } else {
+                conn.execute(
+                    "DELETE FROM user_tags WHERE user_id = ?1 AND tag_id = ?2",
+                    params![user_id, tag_id]
+                )?;
+            }
+            Ok(())
+        }).await
+    }
+
+    async fn add_wait_time(&self, searcher: &Searcher, matched_at: i64) -> Result<()> {
+        let searcher = searcher.clone();
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO wait_times (user_id, gender, chat_type, waited, matched_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    searcher.user_id,
+                    searcher.searcher_gender as i32,
+                    searcher.chat_type as i32,
+                    (matched_at - searcher.joined_at).max(0),
+                    matched_at
+                ]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_wait_times(&self, since: i64) -> Result<Vec<(Gender, ChatType, i64)>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT gender, chat_type, waited FROM wait_times WHERE matched_at >= ?1"
+            )?;
+            let waits = stmt.query_map(params![since], |row| {
+                Ok((
+                    Gender::from(row.get::<_, i32>(0)?),
+                    ChatType::from(row.get::<_, i32>(1)?),
+                    row.get(2)?,
+                ))
+            })?;
+            waits.collect()
+        }).await
+    }
+
+    async fn dequeue_user(&self, user_id: i64) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute("DELETE FROM queue WHERE user_id = ?1", params![user_id])?;
+            Ok(())
+        }).await
+    }
+
+    async fn update_user_nickname(&self, user_id: i64, new_nickname: &str) -> Result<()> {
+        let new_nickname = new_nickname.to_owned();
+        self.run(move |conn| {
+            conn.execute(
+                "UPDATE users SET nickname = ?1 WHERE id = ?2",
+                params![new_nickname, user_id]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn update_user_age(&self, user_id: i64, new_age: u8) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute("UPDATE users SET age = ?1 WHERE id = ?2", params![new_age, user_id])?;
+            Ok(())
+        }).await
+    }
+
+    async fn update_user_gender(&self, user_id: i64, new_gender: Gender) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "UPDATE users SET gender = ?1 WHERE id = ?2",
+                params![new_gender as i32, user_id]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn create_chat(&self, user_id: i64, partner_id: i64, chat_type: ChatType) -> Result<bool> {
+        self.run(move |conn| {
+            // IMMEDIATE takes the write lock up front, so nothing can ban,
+            // delete or pair either user between the checks and the insert.
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            let available: i64 = tx.query_row(
+                "SELECT COUNT(*) FROM users
+                 WHERE id IN (?1, ?2) AND NOT is_banned
+                     AND NOT EXISTS (SELECT 1 FROM chats WHERE chat_one = users.id OR chat_two = users.id)",
+                params![user_id, partner_id],
+                |row| row.get(0)
+            )?;
+            if available != 2 {
+                return Ok(false);
+            }
+
+            if tx.execute("DELETE FROM queue WHERE user_id = ?1", params![partner_id])? == 0 {
+                return Ok(false);
+            }
+            tx.execute("DELETE FROM queue WHERE user_id = ?1", params![user_id])?;
+            tx.execute(
+                "INSERT INTO chats (chat_one, chat_two, chat_type) VALUES (?1, ?2, ?3)",
+                params![user_id, partner_id, chat_type as i32]
+            )?;
+            tx.commit()?;
+
+            Ok(true)
+        }).await
+    }
+
+    async fn delete_chat_by_id(&self, chat_id: i64) -> Result<()> {
+        self.run(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            let members = tx
+                .query_row(
+                    "DELETE FROM chats WHERE id = ?1 RETURNING chat_one, chat_two",
+                    params![chat_id],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+                )
+                .optional()?;
+
+            // A duplicate row for the same pair may survive; its rows are shared.
+            if let Some((chat_one, chat_two)) = members {
+                for table in ["message_links", "self_destructs"] {
+                    tx.execute(
+                        &format!(
+                            "DELETE FROM {}
+                             WHERE ((sender_chat = ?1 AND receiver_chat = ?2) OR (sender_chat = ?2 AND receiver_chat = ?1))
+                               AND NOT EXISTS (
+                                   SELECT 1 FROM chats
+                                   WHERE (chat_one = ?1 AND chat_two = ?2) OR (chat_one = ?2 AND chat_two = ?1)
+                               )",
+                            table
+                        ),
+                        params![chat_one, chat_two]
+                    )?;
+                }
+            }
+
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_chats(&self) -> Result<Vec<(i64, i64, i64)>> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare("SELECT id, chat_one, chat_two FROM chats ORDER BY id")?;
+            let chats = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+            chats.collect()
+        }).await
+    }
+
+    async fn add_message_link(
+        &self,
+        sender_chat: i64,
+        sender_msg: i32,
+        receiver_chat: i64,
+        receiver_msg: i32
+    ) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO message_links (sender_chat, sender_msg, receiver_chat, receiver_msg)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![sender_chat, sender_msg, receiver_chat, receiver_msg]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_linked_message(&self, chat_id: i64, msg_id: i32) -> Result<Option<(i64, i32)>> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT receiver_chat, receiver_msg FROM message_links WHERE sender_chat = ?1 AND sender_msg = ?2
+                 UNION ALL
+                 SELECT sender_chat, sender_msg FROM message_links WHERE receiver_chat = ?1 AND receiver_msg = ?2
+                 LIMIT 1",
+                params![chat_id, msg_id],
+                |row| Ok((row.get(0)?, row.get(1)?))
+            ).optional()
+        }).await
+    }
+
+    async fn get_relayed_copy(&self, sender_chat: i64, sender_msg: i32) -> Result<Option<(i64, i32)>> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT receiver_chat, receiver_msg FROM message_links WHERE sender_chat = ?1 AND sender_msg = ?2",
+                params![sender_chat, sender_msg],
+                |row| Ok((row.get(0)?, row.get(1)?))
+            ).optional()
+        }).await
+    }
+
+    async fn get_last_relayed_message(&self, sender_chat: i64) -> Result<Option<(i32, i64, i32)>> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT sender_msg, receiver_chat, receiver_msg FROM message_links
+                 WHERE sender_chat = ?1 ORDER BY sender_msg DESC LIMIT 1",
+                params![sender_chat],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            ).optional()
+        }).await
+    }
+
+    async fn delete_message_link(&self, sender_chat: i64, sender_msg: i32) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "DELETE FROM message_links WHERE sender_chat = ?1 AND sender_msg = ?2",
+                params![sender_chat, sender_msg]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn add_self_destruct(
+        &self,
+        receiver_chat: i64,
+        receiver_msg: i32,
+        sender_chat: i64,
+        delete_at: Option<i64>,
+        anchor: Option<i32>
+    ) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO self_destructs (receiver_chat, receiver_msg, sender_chat, delete_at, anchor)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![receiver_chat, receiver_msg, sender_chat, delete_at, anchor]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_self_destruct_timers(&self) -> Result<Vec<(i64, i32, i64)>> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT receiver_chat, receiver_msg, delete_at FROM self_destructs WHERE delete_at IS NOT NULL"
+            )?;
+            let timers = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+            timers.collect()
+        }).await
+    }
+
+    async fn remove_self_destruct(&self, receiver_chat: i64, receiver_msg: i32) -> Result<bool> {
+        self.run(move |conn| {
+            let removed = conn.execute(
+                "DELETE FROM self_destructs WHERE receiver_chat = ?1 AND receiver_msg = ?2",
+                params![receiver_chat, receiver_msg]
+            )?;
+            Ok(removed > 0)
+        }).await
+    }
+
+    async fn get_view_once_anchor(&self, receiver_chat: i64, receiver_msg: i32) -> Result<Option<i32>> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT anchor FROM self_destructs
+                 WHERE receiver_chat = ?1 AND receiver_msg = ?2 AND anchor IS NOT NULL",
+                params![receiver_chat, receiver_msg],
+                |row| row.get(0)
+            ).optional()
+        }).await
+    }
+
+    async fn take_view_once(&self, receiver_chat: i64, anchor: i32) -> Result<Vec<i32>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "DELETE FROM self_destructs WHERE receiver_chat = ?1 AND anchor = ?2 RETURNING receiver_msg"
+            )?;
+            let ids = stmt.query_map(params![receiver_chat, anchor], |row| row.get(0))?;
+            ids.collect()
+        }).await
+    }
+
+    async fn take_dialog_self_destructs(&self, user_id: i64, partner_id: i64) -> Result<Vec<(i64, i32)>> {
+        self.run(move |conn| {
+            let mut stmt = conn.prepare(
+                "DELETE FROM self_destructs
+                 WHERE (sender_chat = ?1 AND receiver_chat = ?2) OR (sender_chat = ?2 AND receiver_chat = ?1)
+                 RETURNING receiver_chat, receiver_msg"
+            )?;
+            let copies = stmt.query_map(params![user_id, partner_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            copies.collect()
+        }).await
+    }
+
+    async fn get_dialogue_states(&self) -> Result<Vec<(i64, String)>> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare("SELECT chat_id, state FROM dialogues")?;
+            let states = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            states.collect()
+        }).await
+    }
+
+    async fn get_dialogue_state(&self, chat_id: i64) -> Result<Option<String>> {
+        self.run(move |conn| {
+            conn.query_row("SELECT state FROM dialogues WHERE chat_id = ?1", params![chat_id], |row|
+                row.get(0)
+            ).optional()
+        }).await
+    }
+
+    async fn set_dialogue_state(&self, chat_id: i64, state: String) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO dialogues (chat_id, state) VALUES (?1, ?2)
+                 ON CONFLICT(chat_id) DO UPDATE SET state = excluded.state",
+                params![chat_id, state]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn remove_dialogue_state(&self, chat_id: i64) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute("DELETE FROM dialogues WHERE chat_id = ?1", params![chat_id])?;
+            Ok(())
+        }).await
+    }
+
+    async fn add_restart_notice(&self, user_id: i64) -> Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO restart_notices (user_id) VALUES (?1)",
+                params![user_id]
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_restart_notices(&self) -> Result<Vec<i64>> {
+        self.run(|conn| {
+            let mut stmt = conn.prepare("SELECT user_id FROM restart_notices")?;
+            let ids = stmt.query_map([], |row| row.get(0))?;
+            ids.collect()
+        }).await
+    }
+
+    async fn clear_restart_notices(&self) -> Result<()> {
+        self.run(|conn| {
+            conn.execute("DELETE FROM restart_notices", [])?;
+            Ok(())
+        }).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        // Folds the WAL back into the main file so a copy of `db.db` alone is complete.
+        self.run(|conn| conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))).await
+    }
+}
@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+use rusqlite::Connection;
+
+/// Opens pooled connections to the bot database in WAL mode, so readers
+/// never wait for a writer and writers queue up on `busy_timeout` instead
+/// of failing with `SQLITE_BUSY`.
+pub struct SqliteConnectionManager {
+    path: String,
+}
+
+impl SqliteConnectionManager {
+    pub fn new(path: &str) -> Self {
+        SqliteConnectionManager { path: path.to_owned() }
+    }
+}
+
+impl r2d2::ManageConnection for SqliteConnectionManager {
+    type Connection = Connection;
+    type Error = rusqlite::Error;
+
+    fn connect(&self) -> Result<Connection, rusqlite::Error> {
+        let connection = Connection::open(&self.path)?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+
+        Ok(connection)
+    }
+
+    fn is_valid(&self, connection: &mut Connection) -> Result<(), rusqlite::Error> {
+        connection.execute_batch("")
+    }
+
+    fn has_broken(&self, _: &mut Connection) -> bool {
+        false
+    }
+}
@@ -0,0 +1,80 @@
+use std::{ fmt, future::Future, pin::Pin, sync::Arc };
+
+use teloxide::{ dispatching::dialogue::Storage, types::ChatId };
+
+use crate::{ database::{ self, Database }, state::State };
+
+type StorageFuture<T> = Pin<Box<dyn Future<Output = Result<T, DialogueStorageError>> + Send>>;
+
+/// Dialogue storage kept in the `dialogues` table of the bot database, so
+/// dialogs, searches and registrations survive a restart.
+pub struct DialogueStorage {
+    db: Database,
+}
+
+#[derive(Debug)]
+pub enum DialogueStorageError {
+    Database(database::Error),
+    Serialization(serde_json::Error),
+}
+
+impl fmt::Display for DialogueStorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DialogueStorageError::Database(e) => write!(f, "dialogue storage database error: {}", e),
+            DialogueStorageError::Serialization(e) => {
+                write!(f, "dialogue storage serialization error: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DialogueStorageError {}
+
+impl From<database::Error> for DialogueStorageError {
+    fn from(value: database::Error) -> Self {
+        DialogueStorageError::Database(value)
+    }
+}
+
+impl From<serde_json::Error> for DialogueStorageError {
+    fn from(value: serde_json::Error) -> Self {
+        DialogueStorageError::Serialization(value)
+    }
+}
+
+impl DialogueStorage {
+    pub fn new(db: Database) -> Arc<Self> {
+        Arc::new(DialogueStorage { db })
+    }
+}
+
+impl Storage<State> for DialogueStorage {
+    type Error = DialogueStorageError;
+
+    fn remove_dialogue(self: Arc<Self>, chat_id: ChatId) -> StorageFuture<()> {
+        Box::pin(async move {
+            self.db.remove_dialogue_state(chat_id.0).await?;
+
+            Ok(())
+        })
+    }
+
+    fn update_dialogue(self: Arc<Self>, chat_id: ChatId, dialogue: State) -> StorageFuture<()> {
+        Box::pin(async move {
+            let state = serde_json::to_string(&dialogue)?;
+            self.db.set_dialogue_state(chat_id.0, state).await?;
+
+            Ok(())
+        })
+    }
+
+    fn get_dialogue(self: Arc<Self>, chat_id: ChatId) -> StorageFuture<Option<State>> {
+        Box::pin(async move {
+            match self.db.get_dialogue_state(chat_id.0).await? {
+                Some(state) => Ok(Some(serde_json::from_str(&state)?)),
+                None => Ok(None),
+            }
+        })
+    }
+}
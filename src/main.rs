@@ -1,81 +1,147 @@
+mod albums;
 mod callbacks;
 mod command;
 mod commands;
+mod config;
 mod database;
+mod dialogue_storage;
+mod matchmaking;
 mod messages;
 mod models;
+mod outbound;
+mod reconciliation;
+mod restart;
+mod self_destruct;
 mod state;
 mod user_state;
 
-use database::Database;
+use albums::Albums;
+use config::Config;
+use dialogue_storage::DialogueStorage;
+use matchmaking::Matchmaker;
 use state::State;
-use std::env;
-use teloxide::{ dispatching::{ dialogue::{ self, InMemStorage }, UpdateHandler }, prelude::* };
-use tokio::sync::Mutex as TokioMutex;
+use std::{ env, sync::Arc };
+use teloxide::{ dispatching::{ dialogue, UpdateHandler }, prelude::* };
 
 use crate::{
     callbacks::{
         chat_type_callback,
+        interests_callback,
+        media_callback,
         reactions_callback,
         receive_gender,
         receive_set_gender,
         search_callback,
+        viewed_callback,
     },
     command::Command,
     commands::{
-        admin, admin_message, ban, cancel, delete_user, idle, next, premium, referral, rules, start, stop, top, top_rep, unban, user_info
+        admin, admin_message, ban, cancel, delete_user, idle, interests, media, next, partner_age, premium, reconcile, referral, rules, set_language, start, stop, tags, timer, top, top_rep, unban, unsend, user_info
     },
     messages::{
         dialog_search,
         receive_age,
+        receive_edited_message,
         receive_message,
         receive_nickname,
         receive_set_age,
+        receive_set_location,
         receive_set_nickname,
         set_age,
         set_gender,
+        set_location,
         set_name,
     },
 };
 
-use once_cell::sync::OnceCell;
-
-static DATABASE: OnceCell<TokioMutex<Database>> = OnceCell::new();
-
-type Dialog = Dialogue<State, InMemStorage<State>>;
+type Dialog = Dialogue<State, DialogueStorage>;
 type HandlerResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
 
-async fn initilize() {
+async fn initilize() -> Config {
     dotenv::dotenv().ok();
 
-    let token = env::var("TELOXIDE_TOKEN").unwrap();
-    env::set_var("TELOXIDE_TOKEN", token);
     env::set_var("RUST_LOG", "warn");
 
     pretty_env_logger::init();
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     log::info!("Starting bot...");
+
+    config
 }
 
 #[tokio::main]
 async fn main() {
-    initilize().await;
+    let config = Arc::new(initilize().await);
+
+    let db = database::connect(&config.database).await.unwrap();
+
+    let dialogue_storage = DialogueStorage::new(db.clone());
+
+    let bot = outbound::connect(&config.bot.token);
+
+    // Repair first so resumed users are told about the state they will actually find.
+    let report = reconciliation::reconcile(&db).await;
+    let resumed = restart::resume(&bot, &db).await;
+
+    match report {
+        Ok(report) => {
+            reconciliation::notify_users(&bot, &report, &resumed).await;
+
+            if !report.is_empty() {
+                log::warn!("{:?}", report);
+                let _ = bot.send_message(ChatId(config.bot.admin), report.summary()).await;
+            }
+        }
+        Err(e) => log::error!("Reconciliation failed: {}", e),
+    }
+
+    self_destruct::resume(&bot, &db).await;
+
+    let (matchmaker, matches) = Matchmaker::spawn(db.clone(), config.rules.tag_wait).await.unwrap();
+    tokio::spawn(
+        matchmaking::announce_matches(
+            bot.clone(),
+            db.clone(),
+            dialogue_storage.clone(),
+            matchmaker.clone(),
+            matches
+        )
+    );
+
+    let albums = Albums::new(bot.clone(), db.clone(), matchmaker.clone());
+
+    let mut dispatcher = Dispatcher::builder(bot.clone(), schema())
+        .dependencies(dptree::deps![db.clone(), dialogue_storage, config, matchmaker.clone(), albums])
+        .build();
+
+    let shutdown_token = dispatcher.shutdown_token();
+    tokio::spawn(async move {
+        restart::shutdown_signal().await;
 
-    let db = DATABASE.get_or_init(|| TokioMutex::new(Database::new("db.db").unwrap()));
-    let users = db.lock().await.get_all_users().unwrap();
+        if let Ok(shutdown) = shutdown_token.shutdown() {
+            shutdown.await;
+        }
+    });
 
-    let bot = Bot::from_env();
+    dispatcher.dispatch().await;
 
-    // for user in users {
-    //     let _ = bot
-    //         .send_message(ChatId(user.id), "Наш бот перезагрузился")
-    //         .await;
-    // }
+    log::info!("Shutting down...");
 
-    Dispatcher::builder(bot, schema())
-        .dependencies(dptree::deps![InMemStorage::<State>::new()])
-        .enable_ctrlc_handler()
-        .build()
-        .dispatch().await;
+    // Rematches would otherwise keep pairing people who are being told the
+    // bot is going down.
+    let _paused = matchmaker.pause().await;
+    restart::announce(&bot, &db).await;
+    if let Err(e) = db.flush().await {
+        log::error!("Can't flush database: {}", e);
+    }
 }
 
 fn schema() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync + 'static>> {
@@ -85,6 +151,9 @@ fn schema() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync + 'static>>
         ::filter_command::<Command, _>()
         .branch(case![Command::Start].endpoint(start))
         .branch(case![State::Dialog { interlocutor }].branch(case![Command::Stop].endpoint(stop)))
+        .branch(
+            case![State::Dialog { interlocutor }].branch(case![Command::Unsend].endpoint(unsend))
+        )
         .branch(case![Command::Search].endpoint(idle))
         .branch(case![Command::Next].endpoint(next))
         .branch(case![Command::Cancel].endpoint(cancel))
@@ -101,26 +170,54 @@ fn schema() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync + 'static>>
         .branch(case![Command::Message].endpoint(admin_message))
         .branch(case![Command::Delete].endpoint(delete_user))
         .branch(case![Command::Admin].endpoint(admin))
+        .branch(case![Command::Reconcile].endpoint(reconcile))
+        .branch(case![Command::Tags].endpoint(tags))
         .branch(case![Command::Rules].endpoint(rules))
         .branch(case![Command::Unban].endpoint(unban))
         .branch(case![Command::Ban].endpoint(ban))
         .branch(case![Command::UserInfo].endpoint(user_info))
         .branch(case![Command::SetAge].endpoint(set_age))
-        .branch(case![Command::SetGender].endpoint(set_gender));
+        .branch(case![Command::SetGender].endpoint(set_gender))
+        .branch(case![Command::SetLanguage].endpoint(set_language))
+        .branch(case![Command::SetLocation].endpoint(set_location))
+        .branch(case![Command::PartnerAge].endpoint(partner_age))
+        .branch(case![Command::Interests].endpoint(interests))
+        .branch(case![Command::Media].endpoint(media))
+        .branch(case![Command::Timer].endpoint(timer));
 
     let message_handler = Update::filter_message()
         .branch(command_handler)
-        .enter_dialogue::<Message, InMemStorage<State>, State>()
+        .enter_dialogue::<Message, DialogueStorage, State>()
         .branch(dptree::case![State::Idle].endpoint(idle))
         .branch(dptree::case![State::Start].endpoint(start))
         .branch(dptree::case![State::SetAge].endpoint(receive_set_age))
         .branch(dptree::case![State::SetNickname].endpoint(receive_set_nickname))
+        .branch(dptree::case![State::SetLocation].endpoint(receive_set_location))
         .branch(dptree::case![State::ReceiveAge].endpoint(receive_age))
         .branch(dptree::case![State::ReceiveNickname { age }].endpoint(receive_nickname))
         .branch(dptree::case![State::Search].endpoint(receive_message))
         .branch(dptree::case![State::Dialog { interlocutor }].endpoint(receive_message));
 
+    let edited_message_handler = Update::filter_edited_message().branch(
+        case![State::Dialog { interlocutor }].endpoint(receive_edited_message)
+    );
+
     let callback_query_handler = Update::filter_callback_query()
+        .branch(
+            dptree
+                ::filter(|q: CallbackQuery| q.data.as_deref().is_some_and(|d| d.starts_with("media_")))
+                .endpoint(media_callback)
+        )
+        .branch(
+            dptree
+                ::filter(|q: CallbackQuery| q.data.as_deref().is_some_and(|d| d.starts_with("tag_")))
+                .endpoint(interests_callback)
+        )
+        .branch(
+            dptree
+                ::filter(|q: CallbackQuery| q.data.as_deref() == Some("viewed"))
+                .endpoint(viewed_callback)
+        )
         .branch(case![State::ReceiveGender { age, nickname }].endpoint(receive_gender))
         .branch(case![State::SearchChooseChatType { gender }].endpoint(chat_type_callback))
         .branch(dptree::case![State::SearchChooseGender])
@@ -130,7 +227,8 @@ fn schema() -> UpdateHandler<Box<dyn std::error::Error + Send + Sync + 'static>>
         .endpoint(search_callback);
 
     dialogue
-        ::enter::<Update, InMemStorage<State>, State, _>()
+        ::enter::<Update, DialogueStorage, State, _>()
         .branch(message_handler)
+        .branch(edited_message_handler)
         .branch(callback_query_handler)
 }
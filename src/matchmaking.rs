@@ -0,0 +1,910 @@
+use std::{ collections::VecDeque, fmt, sync::Arc, time::Duration };
+
+use teloxide::{
+    payloads::SendMessageSetters,
+    requests::Requester,
+    types::{ ChatId, InlineKeyboardButton, InlineKeyboardMarkup },
+    ApiError,
+    RequestError,
+};
+use tokio::{ sync::{ mpsc, oneshot }, time::MissedTickBehavior };
+
+use crate::{
+    database::{ self, Database },
+    dialogue_storage::DialogueStorage,
+    models::{ chat_type::ChatType, gender::Gender, searcher::Searcher, tag::Tag, user::User },
+    outbound::Bot,
+    self_destruct,
+    state::State,
+    user_state::UserState,
+    Dialog,
+    HandlerResult,
+};
+
+/// How often waiting searchers are paired again, so those who waited out
+/// their tag preference don't depend on someone new joining.
+const REMATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Two searchers the engine has just paired. The chat row already exists.
+#[derive(Debug, Clone)]
+pub struct Match {
+    /// The side that was being paired, which decides the chat type.
+    pub searcher: Searcher,
+    /// The side that was waiting.
+    pub partner: Searcher,
+    /// Ids of the tags both sides picked.
+    pub shared_tags: Vec<i32>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Database(database::Error),
+    Stopped,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Database(e) => write!(f, "matchmaking database error: {}", e),
+            Error::Stopped => write!(f, "matchmaking engine is not running"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<database::Error> for Error {
+    fn from(value: database::Error) -> Self {
+        Error::Database(value)
+    }
+}
+
+/// What happened to a searcher that asked to join the queue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinOutcome {
+    /// Paired right away; the [`Match`] has been sent to the announcer.
+    Matched,
+    Waiting,
+    /// Still in a chat, e.g. a second `/next` raced the first one's match.
+    InDialog,
+    Banned,
+}
+
+enum Request {
+    Join(Searcher, oneshot::Sender<Result<JoinOutcome, Error>>),
+    Leave(i64, oneshot::Sender<Result<bool, Error>>),
+    Kick(i64, oneshot::Sender<Result<Option<i64>, Error>>),
+    Requeue(Searcher, oneshot::Sender<Result<JoinOutcome, Error>>),
+    SetRegion(i64, Option<String>, oneshot::Sender<Result<(), Error>>),
+    Pause(oneshot::Sender<()>, oneshot::Receiver<()>),
+}
+
+/// Handle to the matchmaking engine.
+#[derive(Clone)]
+pub struct Matchmaker {
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+impl Matchmaker {
+    /// Starts the engine with the searchers already persisted in the queue.
+    /// Searchers with tags hold out for a partner sharing one for `tag_wait`
+    /// seconds. Every pairing the engine makes is sent to the returned receiver.
+    pub async fn spawn(
+        db: Database,
+        tag_wait: i64
+    ) -> Result<(Matchmaker, mpsc::UnboundedReceiver<Match>), Error> {
+        let waiting: VecDeque<Searcher> = db.get_queue().await?.into();
+        let (requests, requests_rx) = mpsc::unbounded_channel();
+        let (matches, matches_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(Engine { db, waiting, matches, tag_wait }.run(requests_rx));
+
+        Ok((Matchmaker { requests }, matches_rx))
+    }
+
+    /// Adds the searcher to the queue or pairs them right away.
+    pub async fn join(&self, searcher: Searcher) -> Result<JoinOutcome, Error> {
+        let (reply, response) = oneshot::channel();
+        self.requests.send(Request::Join(searcher, reply)).map_err(|_| Error::Stopped)?;
+        response.await.map_err(|_| Error::Stopped)?
+    }
+
+    /// Takes the user off the queue. Returns `false` if they were not waiting,
+    /// which includes having been matched just before the request arrived.
+    pub async fn leave(&self, user_id: i64) -> Result<bool, Error> {
+        let (reply, response) = oneshot::channel();
+        self.requests.send(Request::Leave(user_id, reply)).map_err(|_| Error::Stopped)?;
+        response.await.map_err(|_| Error::Stopped)?
+    }
+
+    /// Puts a searcher whose match fell through back in the queue, keeping
+    /// their place in line.
+    pub async fn requeue(&self, searcher: Searcher) -> Result<JoinOutcome, Error> {
+        let (reply, response) = oneshot::channel();
+        self.requests.send(Request::Requeue(searcher, reply)).map_err(|_| Error::Stopped)?;
+        response.await.map_err(|_| Error::Stopped)?
+    }
+
+    /// Takes the user off the queue and out of their chat, returning the
+    /// interlocutor they were with.
+    pub async fn kick(&self, user_id: i64) -> Result<Option<i64>, Error> {
+        let (reply, response) = oneshot::channel();
+        self.requests.send(Request::Kick(user_id, reply)).map_err(|_| Error::Stopped)?;
+        response.await.map_err(|_| Error::Stopped)?
+    }
+
+    /// Stores the user's region, or forgets it with `None`, including for
+    /// their search if they are waiting.
+    pub async fn set_region(&self, user_id: i64, region: Option<String>) -> Result<(), Error> {
+        let (reply, response) = oneshot::channel();
+        self.requests.send(Request::SetRegion(user_id, region, reply)).map_err(|_| Error::Stopped)?;
+        response.await.map_err(|_| Error::Stopped)?
+    }
+
+    /// Stops the engine once it has finished the requests sent so far. It
+    /// stays paused until the returned guard is dropped, then reloads the
+    /// queue from the database.
+    pub async fn pause(&self) -> Result<Paused, Error> {
+        let (paused, ack) = oneshot::channel();
+        let (resume, resumed) = oneshot::channel();
+        self.requests.send(Request::Pause(paused, resumed)).map_err(|_| Error::Stopped)?;
+        ack.await.map_err(|_| Error::Stopped)?;
+
+        Ok(Paused { _resume: resume })
+    }
+}
+
+/// Keeps the engine paused while alive.
+pub struct Paused {
+    _resume: oneshot::Sender<()>,
+}
+
+/// Owns the waiting searchers. Requests are handled one at a time, so the
+/// in-memory queue needs no locking; the `queue` table is only a mirror that
+/// lets searches survive a restart. Whichever of a join, leave or kick for the
+/// same user arrives first wins, and `create_chat` re-checks everything the
+/// engine doesn't own (bans, deletions, existing chats) in one transaction.
+struct Engine {
+    db: Database,
+    waiting: VecDeque<Searcher>,
+    matches: mpsc::UnboundedSender<Match>,
+    tag_wait: i64,
+}
+
+impl Engine {
+    async fn run(mut self, mut requests: mpsc::UnboundedReceiver<Request>) {
+        let mut rematch = tokio::time::interval(REMATCH_INTERVAL);
+        rematch.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                request = requests.recv() => {
+                    match request {
+                        Some(Request::Join(searcher, reply)) => {
+                            let _ = reply.send(self.join(searcher).await);
+                        }
+                        Some(Request::Leave(user_id, reply)) => {
+                            let _ = reply.send(self.leave(user_id).await);
+                        }
+                        Some(Request::Kick(user_id, reply)) => {
+                            let _ = reply.send(self.kick(user_id).await);
+                        }
+                        Some(Request::Requeue(searcher, reply)) => {
+                            let _ = reply.send(self.requeue(searcher).await);
+                        }
+                        Some(Request::SetRegion(user_id, region, reply)) => {
+                            let _ = reply.send(self.set_region(user_id, region).await);
+                        }
+                        Some(Request::Pause(paused, resumed)) => {
+                            let _ = paused.send(());
+                            // Dropping the guard closes the channel, which resumes too.
+                            let _ = resumed.await;
+                            self.reload().await;
+                        }
+                        None => {
+                            break;
+                        }
+                    }
+                }
+                _ = rematch.tick() => self.rematch().await,
+            }
+        }
+    }
+
+    async fn join(&mut self, mut searcher: Searcher) -> Result<JoinOutcome, Error> {
+        self.waiting.retain(|waiting| waiting.user_id != searcher.user_id);
+
+        if let Some(outcome) = self.unavailable(searcher.user_id).await? {
+            self.db.dequeue_user(searcher.user_id).await?;
+            return Ok(outcome);
+        }
+
+        self.db.update_search_filters(
+            searcher.user_id,
+            searcher.search_gender,
+            searcher.chat_type.clone(),
+            searcher.nearby
+        ).await?;
+
+        // Read on every join so /interests changes apply to the next search.
+        searcher.tags = self.db
+            .get_user_tags(searcher.user_id).await?
+            .iter()
+            .map(|tag| tag.id)
+            .collect();
+
+        self.enqueue(searcher).await
+    }
+
+    async fn requeue(&mut self, searcher: Searcher) -> Result<JoinOutcome, Error> {
+        self.waiting.retain(|waiting| waiting.user_id != searcher.user_id);
+
+        if let Some(outcome) = self.unavailable(searcher.user_id).await? {
+            self.db.dequeue_user(searcher.user_id).await?;
+            return Ok(outcome);
+        }
+
+        self.enqueue(searcher).await
+    }
+
+    /// Pairs the searcher right away or queues them behind everyone who
+    /// joined before them.
+    async fn enqueue(&mut self, searcher: Searcher) -> Result<JoinOutcome, Error> {
+        match self.pair(&searcher).await? {
+            JoinOutcome::Waiting => {}
+            JoinOutcome::Matched => {
+                return Ok(JoinOutcome::Matched);
+            }
+            outcome => {
+                self.db.dequeue_user(searcher.user_id).await?;
+                return Ok(outcome);
+            }
+        }
+
+        self.db.add_to_queue(&searcher).await?;
+        let position = self.waiting
+            .iter()
+            .position(|waiting| waiting.joined_at > searcher.joined_at)
+            .unwrap_or(self.waiting.len());
+        self.waiting.insert(position, searcher);
+
+        Ok(JoinOutcome::Waiting)
+    }
+
+    /// Why the user can't be paired right now, if anything stops them.
+    async fn unavailable(&self, user_id: i64) -> Result<Option<JoinOutcome>, Error> {
+        match self.db.get_user(user_id).await? {
+            Some(user) if !user.is_banned => {}
+            _ => {
+                return Ok(Some(JoinOutcome::Banned));
+            }
+        }
+        if self.db.get_chat(user_id).await?.is_some() {
+            return Ok(Some(JoinOutcome::InDialog));
+        }
+
+        Ok(None)
+    }
+
+    /// Pairs the searcher with the best waiting partner, if there is one.
+    /// Returns `Waiting` if nobody fits, or why the searcher itself can't be
+    /// paired; waiting partners are kept in that case.
+    async fn pair(&mut self, searcher: &Searcher) -> Result<JoinOutcome, Error> {
+        while let Some(position) = self.best_partner(searcher) {
+            let partner = self.waiting.remove(position).unwrap();
+
+            match self.db.create_chat(searcher.user_id, partner.user_id, searcher.chat_type.clone()).await {
+                Ok(true) => {
+                    let matched_at = chrono::Utc::now().timestamp();
+                    for side in [searcher, &partner] {
+                        if let Err(e) = self.db.add_wait_time(side, matched_at).await {
+                            log::error!("Can't record wait time: {}", e);
+                        }
+                    }
+
+                    let _ = self.matches.send(Match {
+                        searcher: searcher.clone(),
+                        shared_tags: searcher.shared_tags(&partner),
+                        partner,
+                    });
+                    return Ok(JoinOutcome::Matched);
+                }
+                // Either side may have been banned, deleted or paired behind
+                // our back; only a partner that is gone gets dropped, along
+                // with its queue row so a reload doesn't bring it back.
+                Ok(false) => match self.unavailable(searcher.user_id).await {
+                    Ok(None) => self.db.dequeue_user(partner.user_id).await?,
+                    Ok(Some(outcome)) => {
+                        self.waiting.insert(position, partner);
+                        return Ok(outcome);
+                    }
+                    Err(e) => {
+                        self.waiting.insert(position, partner);
+                        return Err(e);
+                    }
+                }
+                Err(e) => {
+                    self.waiting.insert(position, partner);
+                    return Err(e.into());
+                }
+            }
+        }
+
+        Ok(JoinOutcome::Waiting)
+    }
+
+    /// Position of the best compatible partner for `searcher`. Partners
+    /// without a shared tag only count once neither side holds out for one
+    /// anymore. A shared language wins, then the same region when either side
+    /// searches nearby, then a shared tag, then the longest wait.
+    fn best_partner(&self, searcher: &Searcher) -> Option<usize> {
+        let now = chrono::Utc::now().timestamp();
+        let searcher_settles = !searcher.wants_shared_tags(now, self.tag_wait);
+
+        // `waiting` is kept in join order and `min_by_key` keeps the first of
+        // equal keys, so ties go to whoever has waited longest.
+        self.waiting
+            .iter()
+            .enumerate()
+            .filter(|(_, waiting)| waiting.matches(searcher))
+            .filter_map(|(position, waiting)| {
+                let shares_tags = !waiting.shared_tags(searcher).is_empty();
+                let both_settle = searcher_settles && !waiting.wants_shared_tags(now, self.tag_wait);
+
+                (shares_tags || both_settle).then(|| {
+                    let rank = (
+                        !waiting.shares_language(searcher),
+                        !waiting.is_nearby(searcher),
+                        !shares_tags,
+                    );
+                    (position, rank)
+                })
+            })
+            .min_by_key(|(_, rank)| *rank)
+            .map(|(position, _)| position)
+    }
+
+    /// Pairs waiting searchers with each other, which only finds anyone after
+    /// a tag preference ran out.
+    async fn rematch(&mut self) {
+        let mut position = 0;
+
+        while position < self.waiting.len() {
+            let searcher = self.waiting.remove(position).unwrap();
+
+            match self.pair(&searcher).await {
+                Ok(JoinOutcome::Matched) => {}
+                Ok(JoinOutcome::Waiting) => {
+                    self.waiting.insert(position, searcher);
+                    position += 1;
+                }
+                Ok(JoinOutcome::InDialog | JoinOutcome::Banned) => {
+                    if let Err(e) = self.db.dequeue_user(searcher.user_id).await {
+                        log::error!("{}", e);
+                    }
+                }
+                Err(e) => {
+                    log::error!("{}", e);
+                    self.waiting.insert(position, searcher);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Picks up queue changes made while the engine was paused.
+    async fn reload(&mut self) {
+        match self.db.get_queue().await {
+            Ok(waiting) => self.waiting = waiting.into(),
+            Err(e) => log::error!("Can't reload the queue: {}", e),
+        }
+    }
+
+    async fn leave(&mut self, user_id: i64) -> Result<bool, Error> {
+        let before = self.waiting.len();
+        self.waiting.retain(|waiting| waiting.user_id != user_id);
+        self.db.dequeue_user(user_id).await?;
+
+        Ok(self.waiting.len() != before)
+    }
+
+    async fn set_region(&mut self, user_id: i64, region: Option<String>) -> Result<(), Error> {
+        self.db.update_region(user_id, region.as_deref()).await?;
+
+        for waiting in self.waiting.iter_mut().filter(|waiting| waiting.user_id == user_id) {
+            waiting.region = region.clone();
+        }
+
+        Ok(())
+    }
+
+    async fn kick(&mut self, user_id: i64) -> Result<Option<i64>, Error> {
+        self.leave(user_id).await?;
+
+        Ok(self.db.delete_chat(user_id).await?)
+    }
+}
+
+/// Puts the user into search and tells them how it went. The dialogue is moved
+/// to `Search` before joining so the announcer's `Dialog` always lands last.
+pub async fn search(
+    bot: &Bot,
+    dialog: &Dialog,
+    db: &Database,
+    matchmaker: &Matchmaker,
+    searcher: Searcher
+) -> HandlerResult {
+    let user_id = searcher.user_id;
+
+    dialog.update(State::Search).await?;
+    db.set_user_state(user_id, UserState::Search).await?;
+
+    match matchmaker.join(searcher).await {
+        Ok(JoinOutcome::Matched) => {}
+        Ok(JoinOutcome::Waiting) => {
+            let cancel = [InlineKeyboardButton::callback("❌ Отменить", "cancel")];
+            bot
+                .send_message(dialog.chat_id(), "Ищу...")
+                .reply_markup(InlineKeyboardMarkup::new([cancel])).await?;
+        }
+        Ok(JoinOutcome::InDialog) => {
+            if let Some(partner) = db.get_chat(user_id).await? {
+                dialog.update(State::Dialog { interlocutor: partner as u64 }).await?;
+                db.set_user_state(user_id, UserState::Dialog).await?;
+            }
+            bot.send_message(dialog.chat_id(), "Ты уже в диалоге!").await?;
+        }
+        Ok(JoinOutcome::Banned) => {
+            dialog.update(State::Idle).await?;
+            db.set_user_state(user_id, UserState::Idle).await?;
+            bot.send_message(dialog.chat_id(), "Вы заблокаированы!").await?;
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            dialog.update(State::Idle).await?;
+            db.set_user_state(user_id, UserState::Idle).await?;
+            bot.send_message(dialog.chat_id(), "Ой! Голова кружится...").await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Takes a banned or deleted user out of matching and tells their
+/// interlocutor, if any, that the dialog is over.
+pub async fn remove_user(
+    bot: &Bot,
+    db: &Database,
+    matchmaker: &Matchmaker,
+    user_id: i64
+) -> Result<(), Error> {
+    let _ = db.set_user_state(user_id, UserState::Idle).await;
+
+    if let Some(partner) = matchmaker.kick(user_id).await? {
+        db.set_user_state(partner, UserState::Idle).await?;
+        self_destruct::clear_dialog(bot, db, user_id, partner).await;
+        let sent = bot.send_message(
+            ChatId(partner),
+            "Твой собеседник покинул диалог!\n\n/next - найти нового собеседника"
+        ).await;
+        check_reachable(db, partner, sent).await;
+    }
+
+    Ok(())
+}
+
+/// Whether a request failed because the user blocked the bot, deleted their
+/// account or never started it.
+pub fn is_unreachable(e: &RequestError) -> bool {
+    matches!(
+        e,
+        RequestError::Api(
+            ApiError::BotBlocked | ApiError::UserDeactivated | ApiError::CantInitiateConversation
+        )
+    )
+}
+
+/// Ends the chat and search of a user the bot can no longer reach, leaves them
+/// out of broadcasts and tells their interlocutor, if any, what happened.
+pub async fn deactivate_user(
+    bot: &Bot,
+    db: &Database,
+    matchmaker: &Matchmaker,
+    user_id: i64
+) -> Result<(), Error> {
+    db.set_user_active(user_id, false).await?;
+    db.set_user_state(user_id, UserState::Idle).await?;
+
+    if let Some(partner) = matchmaker.kick(user_id).await? {
+        db.set_user_state(partner, UserState::Idle).await?;
+        self_destruct::clear_dialog(bot, db, user_id, partner).await;
+        let _ = bot.send_message(
+            ChatId(partner),
+            "Собеседник заблокировал бота или удалил аккаунт, диалог завершён 😔\n\n/next - найти нового собеседника"
+        ).await;
+    }
+
+    Ok(())
+}
+
+/// Marks the user inactive if `result` failed because they can't be reached,
+/// for messages sent outside of a chat.
+pub async fn check_reachable<T>(db: &Database, user_id: i64, result: Result<T, RequestError>) {
+    if let Err(e) = result {
+        if is_unreachable(&e) {
+            let _ = db.set_user_active(user_id, false).await;
+        }
+    }
+}
+
+/// Moves both sides of every match into the dialog and tells them about it.
+pub async fn announce_matches(
+    bot: Bot,
+    db: Database,
+    storage: Arc<DialogueStorage>,
+    matchmaker: Matchmaker,
+    mut matches: mpsc::UnboundedReceiver<Match>
+) {
+    while let Some(found) = matches.recv().await {
+        let user = db.get_user(found.searcher.user_id).await;
+        let partner = db.get_user(found.partner.user_id).await;
+
+        let (user, partner) = match (user, partner) {
+            (Ok(Some(user)), Ok(Some(partner))) => (user, partner),
+            _ => {
+                log::error!("Can't load users of match {:?}", found);
+                continue;
+            }
+        };
+
+        // The chat may already be gone, e.g. a kick or ban landed before the
+        // announcement; writing `Dialog` now would strand both users.
+        match db.get_chat(user.id).await {
+            Ok(Some(id)) if id == partner.id => {}
+            _ => {
+                log::warn!("Skipping stale match {:?}", found);
+                continue;
+            }
+        }
+
+        let shared_tags: Vec<Tag> = match db.get_user_tags(user.id).await {
+            Ok(tags) =>
+                tags
+                    .into_iter()
+                    .filter(|tag| found.shared_tags.contains(&tag.id))
+                    .collect(),
+            Err(e) => {
+                log::error!("Can't load tags of {}: {}", user.id, e);
+                Vec::new()
+            }
+        };
+
+        let chat_type = &found.searcher.chat_type;
+        for (me, other, told_other) in [(&user, &partner, false), (&partner, &user, true)] {
+            let _ = db.set_user_state(me.id, UserState::Dialog).await;
+            let _ = Dialog::new(storage.clone(), ChatId(me.id)).update(State::Dialog {
+                interlocutor: other.id as u64,
+            }).await;
+
+            let sent = bot.send_message(
+                ChatId(me.id),
+                match_text(me, other, chat_type, &shared_tags)
+            ).await;
+
+            if let Err(e) = sent {
+                if is_unreachable(&e) {
+                    let _ = Dialog::new(storage.clone(), ChatId(me.id)).update(State::Idle).await;
+
+                    if told_other {
+                        if let Err(e) = deactivate_user(&bot, &db, &matchmaker, me.id).await {
+                            log::error!("{}", e);
+                        }
+                        let _ = Dialog::new(storage.clone(), ChatId(other.id)).update(State::Idle).await;
+                    } else {
+                        // The partner never saw this match, so they just keep searching.
+                        let withdrawn = withdraw_match(&db, &storage, &matchmaker, me.id, found.partner.clone());
+                        if let Err(e) = withdrawn.await {
+                            log::error!("{}", e);
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Ends a match `unreachable` can't be told about without bothering the other
+/// side, who goes back to the queue in their old place.
+async fn withdraw_match(
+    db: &Database,
+    storage: &Arc<DialogueStorage>,
+    matchmaker: &Matchmaker,
+    unreachable: i64,
+    other: Searcher
+) -> Result<(), Error> {
+    let other_id = other.user_id;
+
+    db.set_user_active(unreachable, false).await?;
+    db.set_user_state(unreachable, UserState::Idle).await?;
+    matchmaker.kick(unreachable).await?;
+
+    if matchmaker.requeue(other).await? == JoinOutcome::Banned {
+        db.set_user_state(other_id, UserState::Idle).await?;
+        let _ = Dialog::new(storage.clone(), ChatId(other_id)).update(State::Idle).await;
+    }
+
+    Ok(())
+}
+
+fn match_text(me: &User, other: &User, chat_type: &ChatType, shared_tags: &[Tag]) -> String {
+    let interests = if shared_tags.is_empty() {
+        String::new()
+    } else {
+        let names: Vec<&str> = shared_tags
+            .iter()
+            .map(|tag| tag.name.as_str())
+            .collect();
+        format!("Общие интересы: {}\n\n", names.join(", "))
+    };
+
+    if me.is_premium {
+        format!(
+            "{} \n\nСобеседник найден!\n\n🆔: {}\nПол: {}\nПсевдоним: {} \nВозраст: {}\n\n{}/next - чтобы найти нового собеседника\n/stop - чтобы остановить диалог",
+            if *chat_type == ChatType::Regular {
+                "💬"
+            } else {
+                "🔞"
+            },
+            other.id,
+            if other.gender == Gender::Male {
+                "Мужской ♂"
+            } else {
+                "Женский ♀"
+            },
+            other.nickname,
+            other.age,
+            interests
+        )
+    } else {
+        format!(
+            "Собеседник найден!\n\n{}/next - чтобы найти нового собеседника\n/stop - чтобы остановить диалог",
+            interests
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::sqlite::SqliteStorage,
+        models::search_gender::SearchGender,
+    };
+
+    /// A fresh SQLite database in the temp directory.
+    fn temp_db(name: &str) -> Database {
+        let path = std::env::temp_dir().join(format!("sin-{}-{}.db", name, std::process::id()));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+
+        Arc::new(SqliteStorage::new(path.to_str().unwrap(), 4).unwrap())
+    }
+
+    async fn add_user(db: &Database, id: i64, gender: Gender) -> User {
+        let user = User::new(id, 20, format!("user{}", id), gender);
+        db.add_user(&user).await.unwrap();
+        user
+    }
+
+    fn searcher(user: &User, search_gender: SearchGender) -> Searcher {
+        Searcher::new(user, search_gender, ChatType::Regular, false)
+    }
+
+    /// A searcher open to anyone who joined `waited` seconds ago.
+    fn waiting_for(id: i64, waited: i64, tags: &[i32]) -> Searcher {
+        let user = User::new(id, 20, String::new(), Gender::Male);
+        let mut searcher = searcher(&user, SearchGender::Any);
+        searcher.joined_at -= waited;
+        searcher.tags = tags.to_vec();
+        searcher
+    }
+
+    fn engine(db: Database, waiting: Vec<Searcher>, tag_wait: i64) -> Engine {
+        let (matches, _) = mpsc::unbounded_channel();
+        Engine { db, waiting: waiting.into(), matches, tag_wait }
+    }
+
+    #[tokio::test]
+    async fn concurrent_joins_make_one_chat() {
+        let db = temp_db("concurrent-joins");
+        let one = add_user(&db, 1, Gender::Male).await;
+        let two = add_user(&db, 2, Gender::Female).await;
+        let (matchmaker, mut matches) = Matchmaker::spawn(db.clone(), 0).await.unwrap();
+
+        let (first, second) = tokio::join!(
+            matchmaker.join(searcher(&one, SearchGender::Female)),
+            matchmaker.join(searcher(&two, SearchGender::Male))
+        );
+        let mut outcomes = [first.unwrap(), second.unwrap()];
+        outcomes.sort_by_key(|outcome| *outcome == JoinOutcome::Matched);
+
+        assert_eq!(outcomes, [JoinOutcome::Waiting, JoinOutcome::Matched]);
+        assert_eq!(db.get_total_chats().await.unwrap(), 1);
+        assert_eq!(db.get_queue_count().await.unwrap(), 0);
+        assert!(matches.recv().await.is_some());
+        assert!(matches.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn leave_racing_a_match_keeps_one_winner() {
+        let db = temp_db("leave-race");
+        let one = add_user(&db, 1, Gender::Male).await;
+        let two = add_user(&db, 2, Gender::Female).await;
+        let (matchmaker, _matches) = Matchmaker::spawn(db.clone(), 0).await.unwrap();
+
+        let waiting = matchmaker.join(searcher(&one, SearchGender::Female)).await.unwrap();
+        assert_eq!(waiting, JoinOutcome::Waiting);
+
+        let (left, joined) = tokio::join!(
+            matchmaker.leave(1),
+            matchmaker.join(searcher(&two, SearchGender::Male))
+        );
+
+        if left.unwrap() {
+            assert_eq!(joined.unwrap(), JoinOutcome::Waiting);
+            assert_eq!(db.get_chat(1).await.unwrap(), None);
+            assert_eq!(db.get_queue_count().await.unwrap(), 1);
+        } else {
+            assert_eq!(joined.unwrap(), JoinOutcome::Matched);
+            assert_eq!(db.get_chat(1).await.unwrap(), Some(2));
+            assert_eq!(db.get_queue_count().await.unwrap(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn banned_searcher_keeps_waiting_partner() {
+        let db = temp_db("ban-race");
+        let one = add_user(&db, 1, Gender::Male).await;
+        let two = add_user(&db, 2, Gender::Female).await;
+        db.add_to_queue(&searcher(&one, SearchGender::Female)).await.unwrap();
+
+        let mut engine = engine(db.clone(), db.get_queue().await.unwrap(), 0);
+
+        // Banned after `join` checked the searcher, right before the pairing.
+        db.ban_user(2).await.unwrap();
+        let outcome = engine.pair(&searcher(&two, SearchGender::Male)).await.unwrap();
+
+        assert_eq!(outcome, JoinOutcome::Banned);
+        assert_eq!(engine.waiting.len(), 1);
+        assert_eq!(engine.waiting[0].user_id, 1);
+        assert_eq!(db.get_queue_count().await.unwrap(), 1);
+        assert_eq!(db.get_total_chats().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn clearing_the_region_updates_waiting_searchers() {
+        let db = temp_db("region");
+        let one = add_user(&db, 1, Gender::Male).await;
+        db.update_region(1, Some("ucfv")).await.unwrap();
+        let one = db.get_user(one.id).await.unwrap().unwrap();
+        db.add_to_queue(&searcher(&one, SearchGender::Female)).await.unwrap();
+
+        let mut engine = engine(db.clone(), db.get_queue().await.unwrap(), 0);
+        engine.set_region(1, None).await.unwrap();
+
+        assert_eq!(engine.waiting[0].region, None);
+        assert_eq!(db.get_user(1).await.unwrap().unwrap().region, None);
+        assert_eq!(db.get_queue().await.unwrap()[0].region, None);
+    }
+
+    #[tokio::test]
+    async fn requeued_searcher_keeps_their_place() {
+        let db = temp_db("requeue");
+        let one = add_user(&db, 1, Gender::Male).await;
+        let two = add_user(&db, 2, Gender::Male).await;
+        let mut later = searcher(&one, SearchGender::Female);
+        later.joined_at -= 10;
+        db.add_to_queue(&later).await.unwrap();
+
+        let mut engine = engine(db.clone(), db.get_queue().await.unwrap(), 0);
+        let mut earlier = searcher(&two, SearchGender::Female);
+        earlier.joined_at -= 60;
+        let outcome = engine.requeue(earlier.clone()).await.unwrap();
+
+        assert_eq!(outcome, JoinOutcome::Waiting);
+        assert_eq!(engine.waiting[0].user_id, 2);
+        let queue = db.get_queue().await.unwrap();
+        assert_eq!((queue[0].user_id, queue[0].joined_at), (2, earlier.joined_at));
+    }
+
+    #[tokio::test]
+    async fn resuming_reloads_the_queue() {
+        let db = temp_db("pause");
+        let one = add_user(&db, 1, Gender::Male).await;
+        let two = add_user(&db, 2, Gender::Female).await;
+        let (matchmaker, _matches) = Matchmaker::spawn(db.clone(), 0).await.unwrap();
+        matchmaker.join(searcher(&one, SearchGender::Female)).await.unwrap();
+
+        let paused = matchmaker.pause().await.unwrap();
+        db.dequeue_user(1).await.unwrap();
+        drop(paused);
+
+        let outcome = matchmaker.join(searcher(&two, SearchGender::Male)).await.unwrap();
+        assert_eq!(outcome, JoinOutcome::Waiting);
+    }
+
+    #[tokio::test]
+    async fn stale_queue_entry_is_skipped() {
+        let db = temp_db("stale-entry");
+        let one = add_user(&db, 1, Gender::Male).await;
+        let two = add_user(&db, 2, Gender::Male).await;
+        let three = add_user(&db, 3, Gender::Female).await;
+        db.add_to_queue(&searcher(&one, SearchGender::Female)).await.unwrap();
+        db.add_to_queue(&searcher(&two, SearchGender::Female)).await.unwrap();
+
+        let (matchmaker, mut matches) = Matchmaker::spawn(db.clone(), 0).await.unwrap();
+        db.ban_user(1).await.unwrap();
+
+        let outcome = matchmaker.join(searcher(&three, SearchGender::Male)).await.unwrap();
+        let found = matches.recv().await.unwrap();
+
+        assert_eq!(outcome, JoinOutcome::Matched);
+        assert_eq!((found.searcher.user_id, found.partner.user_id), (3, 2));
+        assert_eq!(db.get_chat(1).await.unwrap(), None);
+        assert_eq!(db.get_queue_count().await.unwrap(), 0);
+    }
+
+    #[test]
+    fn shared_tags_rank_above_waiting_longer() {
+        let waiting = vec![
+            waiting_for(1, 60, &[]),
+            waiting_for(2, 0, &[7]),
+            waiting_for(3, 0, &[8]),
+        ];
+        let engine = engine(temp_db("tag-rank"), waiting, 30);
+
+        // Fresh searchers with tags only take partners sharing one.
+        assert_eq!(engine.best_partner(&waiting_for(4, 0, &[7])), Some(1));
+        assert_eq!(engine.best_partner(&waiting_for(4, 0, &[9])), None);
+        // Once settled, a shared tag still beats the longest wait.
+        assert_eq!(engine.best_partner(&waiting_for(4, 60, &[8])), Some(2));
+        // Without one, only partners who settled too are left.
+        assert_eq!(engine.best_partner(&waiting_for(4, 60, &[9])), Some(0));
+    }
+
+    #[test]
+    fn shared_language_ranks_first() {
+        let mut english = waiting_for(1, 60, &[7]);
+        english.language = Some("en".to_string());
+        let mut russian = waiting_for(2, 0, &[]);
+        russian.language = Some("ru".to_string());
+        let engine = engine(temp_db("language-rank"), vec![english, russian], 30);
+
+        let mut searcher = waiting_for(3, 60, &[7]);
+        searcher.language = Some("ru".to_string());
+        // Even over a shared tag and a longer wait.
+        assert_eq!(engine.best_partner(&searcher), Some(1));
+
+        // A strict searcher doesn't fall back to other languages.
+        searcher.language = Some("de".to_string());
+        searcher.strict_language = true;
+        assert_eq!(engine.best_partner(&searcher), None);
+    }
+
+    #[test]
+    fn same_region_ranks_above_shared_tags_when_searching_nearby() {
+        let tagged = waiting_for(1, 60, &[7]);
+        let mut local = waiting_for(2, 0, &[]);
+        local.region = Some("ucfv".to_string());
+        let engine = engine(temp_db("region-rank"), vec![tagged, local], 30);
+
+        let mut searcher = waiting_for(3, 60, &[7]);
+        searcher.region = Some("ucfv".to_string());
+        assert_eq!(engine.best_partner(&searcher), Some(0));
+
+        searcher.nearby = true;
+        assert_eq!(engine.best_partner(&searcher), Some(1));
+    }
+}
@@ -1,27 +1,81 @@
-use std::env;
+use std::sync::Arc;
 
 use teloxide::{
-    payloads::{ SendMessageSetters, SendPhotoSetters, SendVideoSetters },
-    requests::Requester,
-    types::{ ChatId, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, Message, MessageId },
-    Bot,
+    payloads::{
+        EditMessageCaptionSetters,
+        CopyMessageSetters,
+        SendAnimationSetters,
+        SendAudioSetters,
+        SendContactSetters,
+        SendDocumentSetters,
+        SendLocationSetters,
+        SendMessageSetters,
+        SendPhotoSetters,
+        SendStickerSetters,
+        SendVenueSetters,
+        SendVideoNoteSetters,
+        SendVideoSetters,
+    },
+    requests::{ HasPayload, Requester },
+    types::{
+        ButtonRequest,
+        ChatId,
+        InlineKeyboardButton,
+        InlineKeyboardMarkup,
+        InputFile,
+        InputMedia,
+        InputMediaAnimation,
+        InputMediaPhoto,
+        InputMediaVideo,
+        KeyboardButton,
+        KeyboardMarkup,
+        KeyboardRemove,
+        Message,
+        MessageId,
+    },
+    ApiError,
+    RequestError,
 };
-use tokio::sync::Mutex as TokioMutex;
 
-use crate::commands::{ idle, stop };
-use crate::{ database::Database, state::State, Dialog, HandlerResult, DATABASE };
+use crate::commands::stop;
+use crate::{
+    albums::Albums,
+    config::{ Config, MediaSettings },
+    database::{ self, Database },
+    matchmaking::{ self, Matchmaker },
+    models::{ chat_type::ChatType, message_kind::MessageKind, region },
+    outbound::Bot,
+    self_destruct,
+    state::State,
+    Dialog,
+    HandlerResult,
+};
+
+/// Awaits a send request, as a reply to `$reply_to` if it is `Some`. The reply
+/// is dropped rather than failing the send if the target message is gone.
+macro_rules! reply {
+    ($request:expr, $reply_to:expr) => {
+        match $reply_to {
+            Some(id) => $request.reply_to_message_id(id).allow_sending_without_reply(true).await,
+            None => $request.await,
+        }
+    };
+}
 
-pub async fn receive_set_age(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
+pub async fn receive_set_age(
+    bot: Bot,
+    dialog: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>
+) -> HandlerResult {
     match msg.text().map(|text| text.parse::<u8>()) {
         Some(Ok(age)) => {
-            if age < 12 {
+            if age < config.rules.min_age {
                 bot.send_message(msg.chat.id, "Эй, ты ещё ребенок!").await?;
                 dialog.update(State::Idle).await?;
             } else {
-                let db = DATABASE.get_or_init(||
-                    TokioMutex::new(Database::new("db.db").unwrap())
-                ).lock().await;
-                db.update_user_age(msg.chat.id.0, age).unwrap();
+                db.update_user_age(msg.chat.id.0, age).await.unwrap();
 
                 bot.send_message(msg.chat.id, "Готово").await?;
 
@@ -36,14 +90,47 @@ pub async fn receive_set_age(bot: Bot, dialog: Dialog, msg: Message) -> HandlerR
     Ok(())
 }
 
-pub async fn receive_set_nickname(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
+const SHARE_LOCATION: &str = "📍 Отправить местоположение";
+const CANCEL_LOCATION: &str = "Отмена";
+
+pub async fn receive_set_location(
+    bot: Bot,
+    dialog: Dialog,
+    msg: Message,
+    matchmaker: Matchmaker
+) -> HandlerResult {
+    if let Some(location) = msg.location() {
+        let region = region::from_location(location.latitude, location.longitude);
+        matchmaker.set_region(msg.chat.id.0, Some(region)).await?;
+        // Don't leave the exact point around in the chat either.
+        let _ = bot.delete_message(msg.chat.id, msg.id).await;
+
+        bot
+            .send_message(msg.chat.id, "Готово! Теперь при поиске можно выбрать собеседников рядом 📍")
+            .reply_markup(KeyboardRemove::new()).await?;
+        dialog.update(State::Idle).await?;
+    } else if msg.text() == Some(CANCEL_LOCATION) {
+        bot.send_message(msg.chat.id, "Отменено").reply_markup(KeyboardRemove::new()).await?;
+        dialog.update(State::Idle).await?;
+    } else {
+        bot.send_message(
+            msg.chat.id,
+            format!("Нажми «{}» или «{}»", SHARE_LOCATION, CANCEL_LOCATION)
+        ).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn receive_set_nickname(
+    bot: Bot,
+    dialog: Dialog,
+    msg: Message,
+    db: Database
+) -> HandlerResult {
     match msg.text().map(ToOwned::to_owned) {
         Some(nickname) => {
-            let db = DATABASE.get_or_init(||
-                TokioMutex::new(Database::new("db.db").unwrap())
-            ).lock().await;
-
-            db.update_user_nickname(msg.chat.id.0, &nickname).unwrap();
+            db.update_user_nickname(msg.chat.id.0, &nickname).await.unwrap();
             bot.send_message(msg.chat.id, "Готово").await?;
 
             dialog.update(State::Idle).await?;
@@ -56,7 +143,15 @@ pub async fn receive_set_nickname(bot: Bot, dialog: Dialog, msg: Message) -> Han
     Ok(())
 }
 
-pub async fn receive_message(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
+pub async fn receive_message(
+    bot: Bot,
+    dialog: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>,
+    albums: Albums,
+    matchmaker: Matchmaker
+) -> HandlerResult {
     if let Some(txt) = msg.text() {
         if txt.contains("search") {
             bot.send_message(msg.chat.id, "Не мешай! Я ищу").await?;
@@ -64,13 +159,12 @@ pub async fn receive_message(bot: Bot, dialog: Dialog, msg: Message) -> HandlerR
         }
 
         if txt.contains("stop") {
-            stop(bot, dialog, msg).await?;
+            stop(bot, dialog, msg, db).await?;
             return Ok(());
         }
     }
-    let db = Database::new("db.db").unwrap();
 
-    let chat = db.get_chat(dialog.chat_id().0);
+    let chat = db.get_chat(dialog.chat_id().0).await;
 
     if chat.is_ok() {
         let chat = chat.unwrap();
@@ -82,71 +176,145 @@ pub async fn receive_message(bot: Bot, dialog: Dialog, msg: Message) -> HandlerR
                 interlocutor: chat as u64,
             }).await?;
 
-            if let Some(voice) = msg.voice() {
-                bot.send_audio(ChatId(chat), InputFile::file_id(&voice.file.id)).await?;
-            } else if let Some(sticker) = msg.sticker() {
-                bot.send_sticker(ChatId(chat), InputFile::file_id(&sticker.file.id)).await?;
-            } else if let Some(photo) = msg.photo() {
-                if let Some(txt) = msg.caption() {
-                    bot
-                        .send_photo(
-                            ChatId(chat),
-                            InputFile::file_id(&photo.last().unwrap().file.id)
-                        )
-                        .caption(txt).await?;
-                } else {
-                    bot.send_photo(
-                        ChatId(chat),
-                        InputFile::file_id(&photo.last().unwrap().file.id)
-                    ).await?;
-                }
-            } else if let Some(video) = msg.video() {
-                if let Some(txt) = msg.caption() {
-                    bot
-                        .send_video(ChatId(chat), InputFile::file_id(&video.file.id))
-                        .caption(txt).await?;
-                } else {
-                    bot.send_video(ChatId(chat), InputFile::file_id(&video.file.id)).await?;
+            // Replies point at the partner's copy of the message, found through
+            // the link stored when it was relayed.
+            let mut reply_to = None;
+            if let Some(rpmsg) = msg.reply_to_message() {
+                if let Some((linked_chat, linked_msg)) = db.get_linked_message(msg.chat.id.0, rpmsg.id.0).await? {
+                    if linked_chat == chat {
+                        reply_to = Some(MessageId(linked_msg));
+                    }
                 }
-            } else if let Some(animation) = msg.animation() {
-                bot.send_animation(ChatId(chat), InputFile::file_id(&animation.file.id)).await?;
-            } else if let Some(sticker) = msg.sticker() {
-                bot.send_sticker(ChatId(chat), InputFile::file_id(&sticker.file.id)).await?;
-            } else if let Some(video_note) = msg.video_note() {
-                bot.send_video_note(ChatId(chat), InputFile::file_id(&video_note.file.id)).await?;
-            } else if let Some(txt) = msg.text() {
-                if
-                    txt.to_lowercase().contains("http") ||
-                    txt.to_lowercase().contains("цп") ||
-                    txt.to_lowercase().contains("детское") ||
-                    txt.to_lowercase().contains("продаю") ||
-                    txt.to_lowercase().contains("продам")
-                {
-                    let admin = env::var("ADMIN").unwrap();
+            }
 
+            let kind = match MessageKind::of(&msg) {
+                Some(kind) => kind,
+                None => {
                     bot.send_message(
-                        ChatId(admin.parse::<i64>().unwrap()),
-                        format!("{} отправил что-то подозрительное!\n\n{}", msg.chat.id.0, txt)
+                        msg.chat.id,
+                        "Такой формат сообщения пока что не поддерживается"
                     ).await?;
+                    return Ok(());
                 }
-                if let Some(rpmsg) = msg.reply_to_message() {
-                    let res = bot
-                        .send_message(ChatId(chat), txt)
-                        .reply_to_message_id(MessageId(rpmsg.id.0 - 1)).await;
-
-                    if res.is_err() {
-                        bot
-                            .send_message(ChatId(chat), txt)
-                            .reply_to_message_id(MessageId(rpmsg.id.0 + 1)).await?;
+            };
+
+            let chat_type = db.get_chat_type(msg.chat.id.0).await?.unwrap_or(ChatType::Regular);
+            if !config.relay_allowed(&chat_type, kind) {
+                bot.send_message(msg.chat.id, "Такие сообщения в этом чате запрещены 🚫").await?;
+                return Ok(());
+            }
+
+            let mut media = media_settings(&db, &config, &chat_type, msg.chat.id.0, chat).await?;
+
+            // Self-destructing copies are always protected, or they could be
+            // saved before the timer runs out.
+            let timer = match kind {
+                MessageKind::Photo | MessageKind::Video =>
+                    db.get_user(msg.chat.id.0).await?.and_then(|user| user.self_destruct),
+                _ => None,
+            };
+            if timer.is_some() {
+                media.protect_content = true;
+            }
+            if timer == Some(self_destruct::VIEW_ONCE) {
+                media.spoiler = true;
+            }
+
+            if msg.media_group_id().is_some() {
+                albums.push(msg, chat, reply_to, media, timer).await;
+                return Ok(());
+            }
+
+            let sent = if let Some(sent) = relay_media(&bot, chat, &msg, &media, timer, reply_to).await {
+                sent
+            } else if msg.voice().is_some() {
+                // `SendVoice` can't be protected; a copy can, and keeps the caption.
+                let request = bot
+                    .copy_message(ChatId(chat), msg.chat.id, msg.id)
+                    .protect_content(media.protect_content);
+                reply!(request, reply_to)
+            } else if let Some(sticker) = msg.sticker() {
+                let mut request = bot.send_sticker(ChatId(chat), InputFile::file_id(&sticker.file.id));
+                request.payload_mut().protect_content = Some(media.protect_content);
+                // `SendSticker` takes the raw id rather than a `MessageId`.
+                reply!(request, reply_to.map(|id| id.0)).map(|sent| sent.id)
+            } else if let Some(video_note) = msg.video_note() {
+                let mut request = bot.send_video_note(
+                    ChatId(chat),
+                    InputFile::file_id(&video_note.file.id)
+                );
+                request.payload_mut().protect_content = Some(media.protect_content);
+                reply!(request, reply_to).map(|sent| sent.id)
+            } else if let Some(venue) = msg.venue() {
+                let request = bot
+                    .send_venue(
+                        ChatId(chat),
+                        venue.location.latitude,
+                        venue.location.longitude,
+                        &venue.title,
+                        &venue.address
+                    )
+                    .protect_content(media.protect_content);
+                reply!(request, reply_to).map(|sent| sent.id)
+            } else if let Some(location) = msg.location() {
+                let request = bot
+                    .send_location(ChatId(chat), location.latitude, location.longitude)
+                    .protect_content(media.protect_content);
+                reply!(request, reply_to).map(|sent| sent.id)
+            } else if let Some(contact) = msg.contact() {
+                let mut request = bot.send_contact(
+                    ChatId(chat),
+                    &contact.phone_number,
+                    &contact.first_name
+                );
+                request.payload_mut().last_name = contact.last_name.clone();
+                request.payload_mut().vcard = contact.vcard.clone();
+                request.payload_mut().protect_content = Some(media.protect_content);
+                reply!(request, reply_to).map(|sent| sent.id)
+            } else if msg.poll().is_some() || msg.dice().is_some() {
+                // Copying keeps the rolled dice value, which a fresh send can't
+                // reproduce. A quiz can only be copied while the bot knows its
+                // answer, which it doesn't for one forwarded from a chat where
+                // it is still open.
+                let request = bot
+                    .copy_message(ChatId(chat), msg.chat.id, msg.id)
+                    .protect_content(media.protect_content);
+                let copied = reply!(request, reply_to);
+                match copied {
+                    Err(e) if !matchmaking::is_unreachable(&e) => {
+                        log::warn!("Can't copy message {} from {}: {}", msg.id.0, msg.chat.id.0, e);
+                        bot.send_message(msg.chat.id, "Не получилось отправить это собеседнику 😔").await?;
+                        return Ok(());
                     }
-                } else {
-                    bot.send_message(ChatId(chat), txt).await?;
+                    copied => copied,
                 }
+            } else if let Some(txt) = msg.text() {
+                report_suspicious(&bot, &config, msg.chat.id.0, txt).await?;
+                let mut request = bot.send_message(ChatId(chat), txt);
+                request.payload_mut().entities = msg.entities().map(<[_]>::to_vec);
+                request.payload_mut().protect_content = Some(media.protect_content);
+                reply!(request, reply_to).map(|sent| sent.id)
             } else {
-                bot.send_message(
-                    msg.chat.id,
-                    "Такой формат сообщения пока что не поддерживается"
-                ).await?;
+                return Ok(());
+            };
+
+            let sent = match sent {
+                Ok(sent) => sent,
+                Err(e) if matchmaking::is_unreachable(&e) => {
+                    matchmaking::deactivate_user(&bot, &db, &matchmaker, chat).await?;
+                    dialog.update(State::Idle).await?;
+                    return Ok(());
+                }
+                Err(e) => {
+                    return Err(e.into());
+                }
+            };
+
+            if let Err(e) = db.add_message_link(msg.chat.id.0, msg.id.0, chat, sent.0).await {
+                log::error!("Can't link message {}: {}", msg.id.0, e);
+            }
+            if let Some(seconds) = timer {
+                self_destruct::register(&bot, &db, msg.chat.id.0, chat, &[sent], seconds, None).await;
             }
         } else {
             bot.send_message(msg.chat.id, "Ты не в диалоге! /search чтобы попасть туда!").await?;
@@ -160,10 +328,228 @@ pub async fn receive_message(bot: Bot, dialog: Dialog, msg: Message) -> HandlerR
     Ok(())
 }
 
-pub async fn receive_age(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult {
+/// Sends the partner a copy of a photo, video, animation, audio or document
+/// message. Returns `None` for other kinds of message.
+pub async fn relay_media(
+    bot: &Bot,
+    chat: i64,
+    msg: &Message,
+    media: &MediaSettings,
+    timer: Option<i32>,
+    reply_to: Option<MessageId>
+) -> Option<Result<MessageId, RequestError>> {
+    // Entities carry the formatting (bold, spoilers, code, custom emoji),
+    // which would otherwise be lost in the plain text.
+    let caption = msg.caption().map(ToOwned::to_owned);
+    let caption_entities = msg.caption_entities().map(<[_]>::to_vec);
+
+    let sent = if let Some(photo) = msg.photo() {
+        let mut request = bot.send_photo(
+            ChatId(chat),
+            InputFile::file_id(&photo.last().unwrap().file.id)
+        );
+        request.payload_mut().caption = caption;
+        request.payload_mut().caption_entities = caption_entities;
+        request.payload_mut().protect_content = Some(media.protect_content);
+        request.payload_mut().has_spoiler = Some(media.spoiler);
+        if timer == Some(self_destruct::VIEW_ONCE) {
+            request.payload_mut().reply_markup = Some(self_destruct::viewed_button().into());
+        }
+        reply!(request, reply_to).map(|sent| sent.id)
+    } else if let Some(video) = msg.video() {
+        let mut request = bot.send_video(ChatId(chat), InputFile::file_id(&video.file.id));
+        request.payload_mut().caption = caption;
+        request.payload_mut().caption_entities = caption_entities;
+        request.payload_mut().protect_content = Some(media.protect_content);
+        request.payload_mut().has_spoiler = Some(media.spoiler);
+        if timer == Some(self_destruct::VIEW_ONCE) {
+            request.payload_mut().reply_markup = Some(self_destruct::viewed_button().into());
+        }
+        reply!(request, reply_to).map(|sent| sent.id)
+    } else if let Some(animation) = msg.animation() {
+        let mut request = bot.send_animation(
+            ChatId(chat),
+            InputFile::file_id(&animation.file.id)
+        );
+        request.payload_mut().caption = caption;
+        request.payload_mut().caption_entities = caption_entities;
+        request.payload_mut().protect_content = Some(media.protect_content);
+        request.payload_mut().has_spoiler = Some(media.spoiler);
+        reply!(request, reply_to).map(|sent| sent.id)
+    } else if let Some(audio) = msg.audio() {
+        let mut request = bot.send_audio(ChatId(chat), InputFile::file_id(&audio.file.id));
+        request.payload_mut().caption = caption;
+        request.payload_mut().caption_entities = caption_entities;
+        request.payload_mut().protect_content = Some(media.protect_content);
+        reply!(request, reply_to).map(|sent| sent.id)
+    } else if let Some(document) = msg.document() {
+        let mut request = bot.send_document(
+            ChatId(chat),
+            InputFile::file_id(&document.file.id)
+        );
+        request.payload_mut().caption = caption;
+        request.payload_mut().caption_entities = caption_entities;
+        request.payload_mut().protect_content = Some(media.protect_content);
+        reply!(request, reply_to).map(|sent| sent.id)
+    } else {
+        return None;
+    };
+
+    Some(sent)
+}
+
+/// Forwards text that looks like spam or worse to the admin.
+async fn report_suspicious(bot: &Bot, config: &Config, sender: i64, txt: &str) -> HandlerResult {
+    let lowercase = txt.to_lowercase();
+
+    if ["http", "цп", "детское", "продаю", "продам"].iter().any(|word| lowercase.contains(word)) {
+        bot.send_message(
+            ChatId(config.bot.admin),
+            format!("{} отправил что-то подозрительное!\n\n{}", sender, txt)
+        ).await?;
+    }
+
+    Ok(())
+}
+
+/// How media from `sender` is delivered to `receiver`: the sender decides
+/// whether it is protected, the receiver whether it is blurred. Unset
+/// overrides fall back to the chat type's defaults.
+async fn media_settings(
+    db: &Database,
+    config: &Config,
+    chat_type: &ChatType,
+    sender: i64,
+    receiver: i64
+) -> Result<MediaSettings, database::Error> {
+    let defaults = config.media_defaults(chat_type);
+    let sender = db.get_user(sender).await?;
+    let receiver = db.get_user(receiver).await?;
+
+    Ok(MediaSettings {
+        protect_content: sender
+            .and_then(|user| user.protect_content)
+            .unwrap_or(defaults.protect_content),
+        spoiler: receiver.and_then(|user| user.media_spoiler).unwrap_or(defaults.spoiler),
+    })
+}
+
+/// Mirrors an edit made in a dialog onto the partner's copy of the message,
+/// or tells the partner about it when the copy can't be edited in place.
+pub async fn receive_edited_message(
+    bot: Bot,
+    dialog: Dialog,
+    msg: Message,
+    db: Database,
+    config: Arc<Config>,
+    matchmaker: Matchmaker
+) -> HandlerResult {
+    let partner = match db.get_chat(dialog.chat_id().0).await? {
+        Some(partner) => partner,
+        None => {
+            return Ok(());
+        }
+    };
+
+    // Messages from an earlier dialog or never relayed have nothing to edit.
+    let copy = match db.get_linked_message(msg.chat.id.0, msg.id.0).await? {
+        Some((chat, id)) if chat == partner => MessageId(id),
+        _ => {
+            return Ok(());
+        }
+    };
+
+    if let Some(txt) = msg.text().or(msg.caption()) {
+        report_suspicious(&bot, &config, msg.chat.id.0, txt).await?;
+    }
+
+    // Replacing the media drops the spoiler unless it is set again.
+    let chat_type = db.get_chat_type(partner).await?.unwrap_or(ChatType::Regular);
+    let spoiler = media_settings(&db, &config, &chat_type, msg.chat.id.0, partner).await?.spoiler;
+    // So does the button under a view-once copy, which carries it only when it
+    // is its own anchor; album copies share the anchor of a separate message.
+    let markup = match db.get_view_once_anchor(partner, copy.0).await? {
+        Some(anchor) if anchor == copy.0 => Some(self_destruct::viewed_button()),
+        _ => None,
+    };
+
+    let result = if let Some(txt) = msg.text() {
+        let mut request = bot.edit_message_text(ChatId(partner), copy, txt);
+        request.payload_mut().entities = msg.entities().map(<[_]>::to_vec);
+        request.await.map(|_| ())
+    } else if let Some(photo) = msg.photo() {
+        let mut media = InputMediaPhoto::new(InputFile::file_id(&photo.last().unwrap().file.id));
+        media.has_spoiler = spoiler;
+        if let Some(caption) = msg.caption() {
+            media = media.caption(caption);
+        }
+        if let Some(entities) = msg.caption_entities() {
+            media = media.caption_entities(entities.to_vec());
+        }
+        let mut request = bot.edit_message_media(ChatId(partner), copy, InputMedia::Photo(media));
+        request.payload_mut().reply_markup = markup;
+        request.await.map(|_| ())
+    } else if let Some(video) = msg.video() {
+        let mut media = InputMediaVideo::new(InputFile::file_id(&video.file.id));
+        media.has_spoiler = spoiler;
+        if let Some(caption) = msg.caption() {
+            media = media.caption(caption);
+        }
+        if let Some(entities) = msg.caption_entities() {
+            media = media.caption_entities(entities.to_vec());
+        }
+        let mut request = bot.edit_message_media(ChatId(partner), copy, InputMedia::Video(media));
+        request.payload_mut().reply_markup = markup;
+        request.await.map(|_| ())
+    } else if let Some(animation) = msg.animation() {
+        let mut media = InputMediaAnimation::new(InputFile::file_id(&animation.file.id));
+        media.has_spoiler = spoiler;
+        if let Some(caption) = msg.caption() {
+            media = media.caption(caption);
+        }
+        if let Some(entities) = msg.caption_entities() {
+            media = media.caption_entities(entities.to_vec());
+        }
+        let mut request = bot.edit_message_media(ChatId(partner), copy, InputMedia::Animation(media));
+        request.payload_mut().reply_markup = markup;
+        request.await.map(|_| ())
+    } else if let Some(caption) = msg.caption() {
+        let mut request = bot.edit_message_caption(ChatId(partner), copy).caption(caption);
+        request.payload_mut().caption_entities = msg.caption_entities().map(<[_]>::to_vec);
+        request.payload_mut().reply_markup = markup;
+        request.await.map(|_| ())
+    } else {
+        Err(RequestError::Api(ApiError::MessageCantBeEdited))
+    };
+
+    match result {
+        Ok(()) | Err(RequestError::Api(ApiError::MessageNotModified)) => {}
+        Err(e) if matchmaking::is_unreachable(&e) => {
+            matchmaking::deactivate_user(&bot, &db, &matchmaker, partner).await?;
+            dialog.update(State::Idle).await?;
+        }
+        Err(_) => {
+            let notice = match msg.text().or(msg.caption()) {
+                Some(txt) => format!("✏️ Собеседник изменил сообщение:\n\n{}", txt),
+                None => "✏️ Собеседник изменил сообщение".to_owned(),
+            };
+
+            reply!(bot.send_message(ChatId(partner), notice), Some(copy))?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn receive_age(
+    bot: Bot,
+    dialog: Dialog,
+    msg: Message,
+    config: Arc<Config>
+) -> HandlerResult {
     match msg.text().map(|text| text.parse::<u8>()) {
         Some(Ok(age)) => {
-            if age < 12 {
+            if age < config.rules.min_age {
                 bot.send_message(msg.chat.id, "Эй, ты ещё ребенок!").await?;
                 dialog.update(State::Start).await?;
             } else {
@@ -235,3 +621,37 @@ pub async fn set_gender(bot: Bot, dialog: Dialog, msg: Message) -> HandlerResult
 
     Ok(())
 }
+
+pub async fn set_location(
+    bot: Bot,
+    dialog: Dialog,
+    msg: Message,
+    matchmaker: Matchmaker
+) -> HandlerResult {
+    if msg.text().and_then(|txt| txt.split_whitespace().nth(1)) == Some("off") {
+        // Through the engine, so a running search stops preferring the old region.
+        matchmaker.set_region(msg.chat.id.0, None).await?;
+        bot.send_message(msg.chat.id, "Местоположение удалено").await?;
+        return Ok(());
+    }
+    if let Some(State::Dialog { .. } | State::Search) = dialog.get().await? {
+        bot.send_message(msg.chat.id, "Сначала останови диалог или поиск").await?;
+        return Ok(());
+    }
+
+    let buttons = [
+        [KeyboardButton::new(SHARE_LOCATION).request(ButtonRequest::Location)],
+        [KeyboardButton::new(CANCEL_LOCATION)],
+    ];
+    let keyboard = KeyboardMarkup::new(buttons).resize_keyboard(true).one_time_keyboard(true);
+    bot
+        .send_message(
+            msg.chat.id,
+            "📍 Поделись местоположением, чтобы искать собеседников рядом.\n\nСохранится только примерный район размером с город, точные координаты не хранятся.\n\n/setlocation off - удалить местоположение"
+        )
+        .reply_markup(keyboard).await?;
+
+    dialog.update(State::SetLocation).await?;
+
+    Ok(())
+}
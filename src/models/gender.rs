@@ -1,6 +1,8 @@
 use std::fmt;
 
-#[derive(Debug, Clone, PartialEq, Copy)]
+use serde::{ Deserialize, Serialize };
+
+#[derive(Debug, Clone, PartialEq, Copy, Serialize, Deserialize)]
 pub enum Gender {
     Male,
     Female,
@@ -15,16 +17,6 @@ impl fmt::Display for Gender {
     }
 }
 
-impl Gender {
-    pub fn from_str(s: &str) -> Result<Gender, &'static str> {
-        match s.to_lowercase().as_str() {
-            "male" => Ok(Gender::Male),
-            "female" => Ok(Gender::Female),
-            _ => Err("Invalid gender string"),
-        }
-    }
-}
-
 impl From<i32> for Gender {
     fn from(value: i32) -> Self {
         match value {
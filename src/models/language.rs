@@ -0,0 +1,31 @@
+/// Reduces a Telegram `language_code` such as `pt-br` to its primary
+/// language, `pt`. Returns `None` for anything that doesn't look like one.
+pub fn normalize(code: &str) -> Option<String> {
+    let primary = code.split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase();
+
+    if (2..=3).contains(&primary.len()) && primary.chars().all(|c| c.is_ascii_alphabetic()) {
+        Some(primary)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_primary_language() {
+        assert_eq!(normalize("ru").as_deref(), Some("ru"));
+        assert_eq!(normalize("pt-br").as_deref(), Some("pt"));
+        assert_eq!(normalize("EN_us").as_deref(), Some("en"));
+        assert_eq!(normalize("fil").as_deref(), Some("fil"));
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        for code in ["", "e", "engl", "r1", "-ru", "ру"] {
+            assert_eq!(normalize(code), None, "{}", code);
+        }
+    }
+}
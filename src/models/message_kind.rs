@@ -0,0 +1,77 @@
+use serde::Deserialize;
+use teloxide::types::Message;
+
+/// The kinds of message the relay knows how to pass on. Named in config the
+/// same way, e.g. `video_note`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Text,
+    Photo,
+    Video,
+    Animation,
+    Sticker,
+    Voice,
+    VideoNote,
+    Audio,
+    Document,
+    Location,
+    Venue,
+    Contact,
+    Poll,
+    Dice,
+}
+
+impl MessageKind {
+    pub const ALL: [MessageKind; 14] = [
+        MessageKind::Text,
+        MessageKind::Photo,
+        MessageKind::Video,
+        MessageKind::Animation,
+        MessageKind::Sticker,
+        MessageKind::Voice,
+        MessageKind::VideoNote,
+        MessageKind::Audio,
+        MessageKind::Document,
+        MessageKind::Location,
+        MessageKind::Venue,
+        MessageKind::Contact,
+        MessageKind::Poll,
+        MessageKind::Dice,
+    ];
+
+    /// `None` for service messages and anything else the relay can't copy.
+    pub fn of(msg: &Message) -> Option<MessageKind> {
+        if msg.text().is_some() {
+            Some(MessageKind::Text)
+        } else if msg.photo().is_some() {
+            Some(MessageKind::Photo)
+        } else if msg.video().is_some() {
+            Some(MessageKind::Video)
+        } else if msg.animation().is_some() {
+            Some(MessageKind::Animation)
+        } else if msg.sticker().is_some() {
+            Some(MessageKind::Sticker)
+        } else if msg.voice().is_some() {
+            Some(MessageKind::Voice)
+        } else if msg.video_note().is_some() {
+            Some(MessageKind::VideoNote)
+        } else if msg.audio().is_some() {
+            Some(MessageKind::Audio)
+        } else if msg.document().is_some() {
+            Some(MessageKind::Document)
+        } else if msg.venue().is_some() {
+            Some(MessageKind::Venue)
+        } else if msg.location().is_some() {
+            Some(MessageKind::Location)
+        } else if msg.contact().is_some() {
+            Some(MessageKind::Contact)
+        } else if msg.poll().is_some() {
+            Some(MessageKind::Poll)
+        } else if msg.dice().is_some() {
+            Some(MessageKind::Dice)
+        } else {
+            None
+        }
+    }
+}
@@ -1,3 +1,9 @@
 pub mod chat_type;
 pub mod gender;
+pub mod language;
+pub mod message_kind;
+pub mod region;
+pub mod search_gender;
+pub mod searcher;
+pub mod tag;
 pub mod user;
@@ -0,0 +1,59 @@
+const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Geohash length kept for a shared location. Four characters is a cell of
+/// roughly 40 by 20 km, about the size of a city.
+const PRECISION: usize = 4;
+
+/// Reduces a location to the geohash of the coarse region around it, so the
+/// exact coordinates never have to be stored.
+pub fn from_location(latitude: f64, longitude: f64) -> String {
+    let mut latitudes = (-90.0, 90.0);
+    let mut longitudes = (-180.0, 180.0);
+    let mut region = String::with_capacity(PRECISION);
+    let (mut index, mut bits) = (0, 0);
+
+    // Bits alternate between longitude and latitude, longitude first.
+    while region.len() < PRECISION {
+        let (range, value) = if bits % 2 == 0 {
+            (&mut longitudes, longitude)
+        } else {
+            (&mut latitudes, latitude)
+        };
+        let middle = (range.0 + range.1) / 2.0;
+
+        index <<= 1;
+        if value >= middle {
+            index |= 1;
+            range.0 = middle;
+        } else {
+            range.1 = middle;
+        }
+
+        bits += 1;
+        if bits % 5 == 0 {
+            region.push(BASE32[index] as char);
+            index = 0;
+        }
+    }
+
+    region
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_known_geohashes() {
+        assert_eq!(from_location(57.64911, 10.40744), "u4pr");
+        assert_eq!(from_location(55.7558, 37.6173), "ucfv");
+        assert_eq!(from_location(0.0, 0.0), "s000");
+        assert_eq!(from_location(-90.0, -180.0), "0000");
+    }
+
+    #[test]
+    fn nearby_points_share_a_region() {
+        assert_eq!(from_location(55.7558, 37.6173), from_location(55.80, 37.70));
+        assert_ne!(from_location(55.7558, 37.6173), from_location(59.9343, 30.3351));
+    }
+}
@@ -0,0 +1,43 @@
+use serde::{ Deserialize, Serialize };
+
+use super::gender::Gender;
+
+/// Who a searcher wants to be paired with.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SearchGender {
+    Male,
+    Female,
+    Any,
+}
+
+impl SearchGender {
+    /// Labels of the search gender buttons, also used as their callback data.
+    pub const BUTTONS: [&'static str; 3] = ["Мужской ♂", "Женский ♀", "Любой ⚥"];
+
+    pub fn from_button(label: &str) -> Option<SearchGender> {
+        match label {
+            "Мужской ♂" => Some(SearchGender::Male),
+            "Женский ♀" => Some(SearchGender::Female),
+            "Любой ⚥" => Some(SearchGender::Any),
+            _ => None,
+        }
+    }
+
+    pub fn accepts(&self, gender: Gender) -> bool {
+        match self {
+            SearchGender::Male => gender == Gender::Male,
+            SearchGender::Female => gender == Gender::Female,
+            SearchGender::Any => true,
+        }
+    }
+}
+
+impl From<i32> for SearchGender {
+    fn from(value: i32) -> Self {
+        match value {
+            1 => SearchGender::Female,
+            2 => SearchGender::Any,
+            _ => SearchGender::Male,
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use super::{ chat_type::ChatType, gender::Gender, search_gender::SearchGender, user::User };
+
+/// A user waiting in the search queue and who they are looking for.
+#[derive(Debug, Clone)]
+pub struct Searcher {
+    pub user_id: i64,
+    pub search_gender: SearchGender,
+    pub searcher_gender: Gender,
+    pub chat_type: ChatType,
+    pub age: u8,
+    /// Inclusive partner age range; `None` accepts any age.
+    pub partner_age: Option<(u8, u8)>,
+    /// Ids of the searcher's interest tags.
+    pub tags: Vec<i32>,
+    pub language: Option<String>,
+    /// Only accept partners speaking `language`.
+    pub strict_language: bool,
+    pub region: Option<String>,
+    /// Prefer partners from the same region.
+    pub nearby: bool,
+    /// Unix time the user joined the queue; older searchers are matched first.
+    pub joined_at: i64,
+}
+
+impl Searcher {
+    /// Searches with the user's own gender, age, language, region and saved
+    /// partner age range.
+    pub fn new(user: &User, search_gender: SearchGender, chat_type: ChatType, nearby: bool) -> Self {
+        Self {
+            user_id: user.id,
+            search_gender,
+            searcher_gender: user.gender,
+            chat_type,
+            age: user.age,
+            partner_age: user.partner_age,
+            tags: Vec::new(),
+            language: user.language.clone(),
+            strict_language: user.strict_language,
+            region: user.region.clone(),
+            nearby,
+            joined_at: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    /// Both sides accept each other's gender, age and language in the same
+    /// kind of chat.
+    pub fn matches(&self, other: &Searcher) -> bool {
+        self.user_id != other.user_id &&
+            self.search_gender.accepts(other.searcher_gender) &&
+            other.search_gender.accepts(self.searcher_gender) &&
+            self.accepts_age(other.age) &&
+            other.accepts_age(self.age) &&
+            ((!self.strict_language && !other.strict_language) || self.shares_language(other)) &&
+            self.chat_type == other.chat_type
+    }
+
+    /// Both searchers are known to speak the same language.
+    pub fn shares_language(&self, other: &Searcher) -> bool {
+        self.language.is_some() && self.language == other.language
+    }
+
+    /// Either searcher asked for someone nearby and both are from the same
+    /// region.
+    pub fn is_nearby(&self, other: &Searcher) -> bool {
+        (self.nearby || other.nearby) && self.region.is_some() && self.region == other.region
+    }
+
+    /// Ids of the tags both searchers picked.
+    pub fn shared_tags(&self, other: &Searcher) -> Vec<i32> {
+        self.tags
+            .iter()
+            .filter(|tag| other.tags.contains(tag))
+            .copied()
+            .collect()
+    }
+
+    /// Whether the searcher still holds out for a partner with a shared tag,
+    /// which they do for `tag_wait` seconds after joining.
+    pub fn wants_shared_tags(&self, now: i64, tag_wait: i64) -> bool {
+        !self.tags.is_empty() && now - self.joined_at < tag_wait
+    }
+
+    fn accepts_age(&self, age: u8) -> bool {
+        self.partner_age.is_none_or(|(min, max)| (min..=max).contains(&age))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn searcher(id: i64, gender: Gender, search_gender: SearchGender) -> Searcher {
+        let user = User::new(id, 25, String::new(), gender);
+        Searcher::new(&user, search_gender, ChatType::Regular, false)
+    }
+
+    #[test]
+    fn any_gender_needs_the_other_side_to_accept_too() {
+        let any = searcher(1, Gender::Male, SearchGender::Any);
+
+        assert!(any.matches(&searcher(2, Gender::Female, SearchGender::Male)));
+        assert!(any.matches(&searcher(2, Gender::Male, SearchGender::Any)));
+        assert!(!any.matches(&searcher(2, Gender::Male, SearchGender::Female)));
+        assert!(!any.matches(&searcher(2, Gender::Female, SearchGender::Female)));
+        assert!(!any.matches(&searcher(1, Gender::Female, SearchGender::Any)));
+    }
+
+    #[test]
+    fn age_ranges_must_hold_both_ways() {
+        let mut young = searcher(1, Gender::Male, SearchGender::Any);
+        young.age = 20;
+        young.partner_age = Some((18, 25));
+        let mut older = searcher(2, Gender::Female, SearchGender::Any);
+        older.age = 25;
+
+        // Ranges are inclusive, and no range accepts anyone.
+        assert!(young.matches(&older));
+
+        older.partner_age = Some((21, 30));
+        assert!(!young.matches(&older));
+        assert!(!older.matches(&young));
+
+        older.partner_age = Some((20, 30));
+        older.age = 26;
+        assert!(!young.matches(&older));
+    }
+}
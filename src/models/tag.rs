@@ -0,0 +1,6 @@
+/// An interest from the admin-managed catalogue that users pick with /interests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub id: i32,
+    pub name: String,
+}
@@ -1,6 +1,6 @@
 use crate::user_state::UserState;
 
-use super::{ chat_type::ChatType, gender::Gender };
+use super::{ chat_type::ChatType, gender::Gender, search_gender::SearchGender };
 
 #[derive(Debug)]
 pub struct User {
@@ -8,14 +8,34 @@ pub struct User {
     pub nickname: String,
     pub age: u8,
     pub gender: Gender,
-    pub search_gender: Option<Gender>,
+    pub search_gender: Option<SearchGender>,
     pub chat_type: Option<ChatType>,
+    /// Inclusive age range the user wants their partners in.
+    pub partner_age: Option<(u8, u8)>,
+    /// Primary language code, e.g. `ru`.
+    pub language: Option<String>,
+    /// Only pair the user with partners speaking their language, instead of
+    /// just preferring them.
+    pub strict_language: bool,
+    /// Geohash of the coarse region around the location the user shared.
+    pub region: Option<String>,
+    /// The last search preferred partners from the same region.
+    pub nearby: bool,
     pub state: UserState,
     pub reputation: i32,
     pub is_banned: bool,
     pub referrals: u32,
     pub is_premium: bool,
     pub premium_until: i64,
+    /// Overrides the chat type's default for media this user sends.
+    pub protect_content: Option<bool>,
+    /// Overrides the chat type's default for media this user receives.
+    pub media_spoiler: Option<bool>,
+    /// Seconds the user's photos and videos live after being relayed;
+    /// `Some(0)` is view-once.
+    pub self_destruct: Option<i32>,
+    /// `false` once the bot learns the user blocked it or deleted their account.
+    pub is_active: bool,
 }
 impl User {
     pub fn new(id: i64, age: u8, nickname: String, gender: Gender) -> Self {
@@ -26,12 +46,21 @@ impl User {
             gender: gender,
             search_gender: None,
             chat_type: None,
+            partner_age: None,
+            language: None,
+            strict_language: false,
+            region: None,
+            nearby: false,
             state: UserState::Default,
             reputation: 0,
             is_banned: false,
             referrals: 0,
             is_premium: false,
             premium_until: 0,
+            protect_content: None,
+            media_spoiler: None,
+            self_destruct: None,
+            is_active: true,
         }
     }
 }
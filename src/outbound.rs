@@ -0,0 +1,27 @@
+use std::time::Duration;
+
+use teloxide::{
+    adaptors::{ throttle::Limits, Throttle },
+    requests::RequesterExt,
+};
+use tokio::time::{ Interval, MissedTickBehavior };
+
+/// The bot every handler sends through. All requests share one queue that
+/// keeps Telegram's global and per-chat limits, sends in order within a chat
+/// and waits out `RetryAfter` before retrying.
+pub type Bot = Throttle<teloxide::Bot>;
+
+/// Messages per second bulk sends may take out of the global limit of 30, so
+/// a broadcast never crowds out dialogs.
+const BULK_PER_SEC: u64 = 10;
+
+pub fn connect(token: &str) -> Bot {
+    teloxide::Bot::new(token).throttle(Limits::default())
+}
+
+/// Paces bulk traffic such as broadcasts; tick once before each message.
+pub fn bulk_pace() -> Interval {
+    let mut pace = tokio::time::interval(Duration::from_millis(1000 / BULK_PER_SEC));
+    pace.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    pace
+}
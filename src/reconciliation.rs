@@ -0,0 +1,242 @@
+use std::collections::{ HashMap, HashSet };
+
+use teloxide::{ requests::Requester, types::ChatId };
+
+use crate::{
+    database::{ Database, Result },
+    outbound::{ self, Bot },
+    state::State,
+    user_state::UserState,
+};
+
+/// What a reconciliation pass found and repaired.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Chats removed because a side no longer exists, is banned or is already in an older chat.
+    pub removed_chats: Vec<(i64, i64)>,
+    /// Queue entries removed for deleted, banned or already paired users.
+    pub removed_queue_entries: Vec<i64>,
+    /// Users marked as in a dialog that had no chat.
+    pub ended_dialogs: Vec<i64>,
+    /// Users marked as searching that were not in the queue.
+    pub ended_searches: Vec<i64>,
+    /// Users whose `users.state` was brought in line with an existing chat or queue entry.
+    pub restored_states: Vec<i64>,
+    /// Dialogue states rewritten or dropped.
+    pub fixed_dialogues: Vec<i64>,
+}
+
+impl Report {
+    pub fn is_empty(&self) -> bool {
+        self.removed_chats.is_empty() &&
+            self.removed_queue_entries.is_empty() &&
+            self.ended_dialogs.is_empty() &&
+            self.ended_searches.is_empty() &&
+            self.restored_states.is_empty() &&
+            self.fixed_dialogues.is_empty()
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "Reconciliation\n\nRemoved chats: {}\nRemoved queue entries: {}\nEnded dialogs: {}\nEnded searches: {}\nRestored states: {}\nFixed dialogues: {}",
+            self.removed_chats.len(),
+            self.removed_queue_entries.len(),
+            self.ended_dialogs.len(),
+            self.ended_searches.len(),
+            self.restored_states.len(),
+            self.fixed_dialogues.len()
+        )
+    }
+}
+
+/// Brings `users.state`, the queue, chats and dialogue states back in sync.
+/// Chats and the queue are the source of truth for who is paired or waiting;
+/// rows that reference deleted or banned users are dropped, and a removed
+/// chat takes its message links and self-destructs with it.
+pub async fn reconcile(db: &Database) -> Result<Report> {
+    let mut report = Report::default();
+
+    let users: HashMap<i64, _> = db
+        .get_all_users().await?
+        .into_iter()
+        .map(|user| (user.id, user))
+        .collect();
+
+    let member = |id: i64| users.get(&id).is_some_and(|user| !user.is_banned);
+
+    let mut partners: HashMap<i64, i64> = HashMap::new();
+    for (id, chat_one, chat_two) in db.get_chats().await? {
+        let valid =
+            member(chat_one) &&
+            member(chat_two) &&
+            chat_one != chat_two &&
+            !partners.contains_key(&chat_one) &&
+            !partners.contains_key(&chat_two);
+
+        if valid {
+            partners.insert(chat_one, chat_two);
+            partners.insert(chat_two, chat_one);
+        } else {
+            db.delete_chat_by_id(id).await?;
+            report.removed_chats.push((chat_one, chat_two));
+        }
+    }
+
+    let mut queued: HashSet<i64> = HashSet::new();
+    for user_id in db.get_queue().await?.into_iter().map(|searcher| searcher.user_id) {
+        let valid = match users.get(&user_id) {
+            Some(user) => !user.is_banned && !partners.contains_key(&user_id),
+            None => false,
+        };
+
+        if valid {
+            queued.insert(user_id);
+        } else {
+            db.dequeue_user(user_id).await?;
+            report.removed_queue_entries.push(user_id);
+        }
+    }
+
+    for user in users.values() {
+        let expected = if partners.contains_key(&user.id) {
+            UserState::Dialog
+        } else if queued.contains(&user.id) {
+            UserState::Search
+        } else if user.state == UserState::Dialog || user.state == UserState::Search {
+            UserState::Idle
+        } else {
+            continue;
+        };
+
+        if expected == user.state {
+            continue;
+        }
+
+        match (&user.state, &expected) {
+            (UserState::Dialog, UserState::Idle) => report.ended_dialogs.push(user.id),
+            (UserState::Search, UserState::Idle) => report.ended_searches.push(user.id),
+            _ => report.restored_states.push(user.id),
+        }
+        db.set_user_state(user.id, expected).await?;
+    }
+
+    let mut seen: HashSet<i64> = HashSet::new();
+    for (chat_id, raw) in db.get_dialogue_states().await? {
+        seen.insert(chat_id);
+
+        let state = match serde_json::from_str::<State>(&raw) {
+            Ok(state) => state,
+            Err(_) => {
+                db.remove_dialogue_state(chat_id).await?;
+                report.fixed_dialogues.push(chat_id);
+                continue;
+            }
+        };
+
+        if let Some(state) = expected_dialogue(&state, partners.get(&chat_id), queued.contains(&chat_id)) {
+            db.set_dialogue_state(chat_id, serde_json::to_string(&state).unwrap()).await?;
+            report.fixed_dialogues.push(chat_id);
+        }
+    }
+
+    // Paired or waiting users without a stored dialogue would fall back to
+    // `State::Start` and lose track of their chat.
+    for &chat_id in partners.keys().chain(queued.iter()) {
+        if seen.contains(&chat_id) {
+            continue;
+        }
+
+        if let Some(state) = expected_dialogue(&State::Start, partners.get(&chat_id), queued.contains(&chat_id)) {
+            db.set_dialogue_state(chat_id, serde_json::to_string(&state).unwrap()).await?;
+            report.fixed_dialogues.push(chat_id);
+        }
+    }
+
+    Ok(report)
+}
+
+/// The dialogue state a user should be moved to, or `None` if theirs is fine.
+/// States in the middle of registration or settings are left alone.
+fn expected_dialogue(state: &State, partner: Option<&i64>, queued: bool) -> Option<State> {
+    match (state, partner) {
+        (State::Dialog { interlocutor }, Some(&partner)) if *interlocutor == (partner as u64) => None,
+        (State::Dialog { .. } | State::Search | State::Idle | State::Start, Some(&partner)) =>
+            Some(State::Dialog { interlocutor: partner as u64 }),
+        (State::Search, None) if queued => None,
+        (State::Dialog { .. } | State::Idle | State::Start, None) if queued => Some(State::Search),
+        (State::Dialog { .. } | State::Search, None) => Some(State::Idle),
+        _ => None,
+    }
+}
+
+/// Tells users whose dialog or search was ended by the repair what happened,
+/// except those in `already_notified`.
+pub async fn notify_users(bot: &Bot, report: &Report, already_notified: &HashSet<i64>) {
+    let mut notified = already_notified.clone();
+    let mut pace = outbound::bulk_pace();
+
+    for &user_id in &report.ended_dialogs {
+        if notified.insert(user_id) {
+            pace.tick().await;
+            let _ = bot.send_message(
+                ChatId(user_id),
+                "Твой диалог был завершён из-за сбоя 😔\n\n/search - найти нового собеседника"
+            ).await;
+        }
+    }
+
+    for &user_id in &report.ended_searches {
+        if notified.insert(user_id) {
+            pace.tick().await;
+            let _ = bot.send_message(
+                ChatId(user_id),
+                "Поиск был остановлен из-за сбоя 😔\n\n/search - начать поиск заново"
+            ).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        database::sqlite::SqliteStorage,
+        models::{
+            chat_type::ChatType,
+            gender::Gender,
+            search_gender::SearchGender,
+            searcher::Searcher,
+            user::User,
+        },
+    };
+
+    #[tokio::test]
+    async fn chat_with_banned_member_is_removed() {
+        let path = std::env::temp_dir().join(format!("sin-reconcile-{}.db", std::process::id()));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        let db: Database = Arc::new(SqliteStorage::new(path.to_str().unwrap(), 4).unwrap());
+
+        let one = User::new(1, 20, "one".to_string(), Gender::Male);
+        let two = User::new(2, 20, "two".to_string(), Gender::Female);
+        db.add_user(&one).await.unwrap();
+        db.add_user(&two).await.unwrap();
+
+        let searcher = Searcher::new(&one, SearchGender::Female, ChatType::Regular, false);
+        db.add_to_queue(&searcher).await.unwrap();
+        assert!(db.create_chat(2, 1, ChatType::Regular).await.unwrap());
+        db.set_user_state(1, UserState::Dialog).await.unwrap();
+        db.set_user_state(2, UserState::Dialog).await.unwrap();
+        db.add_message_link(1, 10, 2, 20).await.unwrap();
+        db.ban_user(2).await.unwrap();
+
+        let report = reconcile(&db).await.unwrap();
+        assert_eq!(report.removed_chats, vec![(2, 1)]);
+        assert_eq!(db.get_chat(1).await.unwrap(), None);
+        assert_eq!(db.get_relayed_copy(1, 10).await.unwrap(), None);
+        assert_eq!(db.get_user(1).await.unwrap().unwrap().state, UserState::Idle);
+    }
+}
@@ -0,0 +1,116 @@
+use std::{ collections::HashSet, time::Duration };
+
+use teloxide::{ requests::Requester, types::ChatId };
+
+use crate::{ database::Database, outbound::{ self, Bot }, user_state::UserState };
+
+/// Resolves once the process is asked to stop, by Ctrl+C or SIGTERM.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{ signal, SignalKind };
+
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// How long [`announce`] keeps sending, well inside the usual grace period
+/// between SIGTERM and SIGKILL.
+const ANNOUNCE_BUDGET: Duration = Duration::from_secs(10);
+
+/// Warns users who are chatting or searching that the bot is going down and
+/// remembers them so they hear back after the restart. Idle and unreachable
+/// users are left alone.
+pub async fn announce(bot: &Bot, db: &Database) {
+    let users = match db.get_all_users().await {
+        Ok(users) => users,
+        Err(e) => {
+            log::error!("Can't load users before shutdown: {}", e);
+            return;
+        }
+    };
+
+    // Notices are saved first, so everyone hears back after the restart even
+    // if the warnings run out of time.
+    let mut to_warn = Vec::new();
+    for user in users {
+        if !user.is_active || (user.state != UserState::Dialog && user.state != UserState::Search) {
+            continue;
+        }
+
+        if let Err(e) = db.add_restart_notice(user.id).await {
+            log::error!("Can't save restart notice for {}: {}", user.id, e);
+            continue;
+        }
+        to_warn.push(user.id);
+    }
+
+    let deadline = tokio::time::Instant::now() + ANNOUNCE_BUDGET;
+    let mut pace = outbound::bulk_pace();
+    for (sent, &id) in to_warn.iter().enumerate() {
+        let warned = tokio::time::timeout_at(deadline, async {
+            pace.tick().await;
+            bot.send_message(
+                ChatId(id),
+                "Бот перезагружается, подожди немного 🙏\n\nМы напишем, когда он снова заработает"
+            ).await
+        }).await;
+
+        if warned.is_err() {
+            log::warn!("Restart warnings ran out of time after {} of {}", sent, to_warn.len());
+            break;
+        }
+    }
+}
+
+/// Reports to everyone warned by [`announce`] whether their dialog or search
+/// survived the restart. Returns the users that were notified.
+pub async fn resume(bot: &Bot, db: &Database) -> HashSet<i64> {
+    let mut notified = HashSet::new();
+
+    let ids = match db.get_restart_notices().await {
+        Ok(ids) => ids,
+        Err(e) => {
+            log::error!("Can't load restart notices: {}", e);
+            return notified;
+        }
+    };
+
+    let mut pace = outbound::bulk_pace();
+    for id in ids {
+        let state = db
+            .get_user(id).await
+            .ok()
+            .flatten()
+            .map(|user| user.state);
+
+        let text = match state {
+            Some(UserState::Dialog) => "Бот снова работает! Диалог продолжается 💬",
+            Some(UserState::Search) => "Бот снова работает! Поиск продолжается 🔎",
+            Some(_) =>
+                "Бот снова работает, но твоя сессия была завершена 😔\n\n/search - найти нового собеседника",
+            None => {
+                continue;
+            }
+        };
+
+        pace.tick().await;
+        let _ = bot.send_message(ChatId(id), text).await;
+        notified.insert(id);
+    }
+
+    if let Err(e) = db.clear_restart_notices().await {
+        log::error!("Can't clear restart notices: {}", e);
+    }
+
+    notified
+}
@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use teloxide::{
+    requests::Requester,
+    types::{ ChatId, InlineKeyboardButton, InlineKeyboardMarkup, MessageId },
+};
+
+use crate::{ database::{ Database, Result }, outbound::Bot };
+
+/// The `users.self_destruct` value for view-once media.
+pub const VIEW_ONCE: i32 = 0;
+
+/// The longest timer `/timer` accepts. Bots can only delete messages for 48
+/// hours after they were sent.
+pub const MAX_SECONDS: i32 = 24 * 60 * 60;
+
+/// Button under view-once copies that deletes them.
+pub fn viewed_button() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[InlineKeyboardButton::callback("👁 Просмотрено", "viewed")]])
+}
+
+/// Remembers the partner's copies of a self-destructing message and starts
+/// their timers. View-once copies are deleted together when the message
+/// `anchor` points at is marked viewed; each copy is its own anchor by default.
+pub async fn register(
+    bot: &Bot,
+    db: &Database,
+    sender: i64,
+    receiver: i64,
+    copies: &[MessageId],
+    seconds: i32,
+    anchor: Option<MessageId>
+) {
+    let delete_at = if seconds == VIEW_ONCE {
+        None
+    } else {
+        Some(chrono::Utc::now().timestamp() + (seconds as i64))
+    };
+
+    for copy in copies {
+        let anchor = delete_at.is_none().then(|| anchor.unwrap_or(*copy).0);
+
+        if let Err(e) = db.add_self_destruct(receiver, copy.0, sender, delete_at, anchor).await {
+            log::error!("Can't save self-destruct of {}: {}", copy.0, e);
+            continue;
+        }
+        if let Some(delete_at) = delete_at {
+            schedule(bot.clone(), db.clone(), receiver, copy.0, delete_at);
+        }
+    }
+}
+
+fn schedule(bot: Bot, db: Database, chat: i64, msg: i32, delete_at: i64) {
+    tokio::spawn(async move {
+        let left = delete_at - chrono::Utc::now().timestamp();
+        if left > 0 {
+            tokio::time::sleep(Duration::from_secs(left as u64)).await;
+        }
+
+        // Already cleaned up when the dialog ended.
+        if let Ok(false) = db.remove_self_destruct(chat, msg).await {
+            return;
+        }
+        let _ = bot.delete_message(ChatId(chat), MessageId(msg)).await;
+    });
+}
+
+/// Restarts the timers saved before a restart. Overdue copies go right away.
+pub async fn resume(bot: &Bot, db: &Database) {
+    match db.get_self_destruct_timers().await {
+        Ok(timers) => {
+            for (chat, msg, delete_at) in timers {
+                schedule(bot.clone(), db.clone(), chat, msg, delete_at);
+            }
+        }
+        Err(e) => log::error!("Can't load self-destruct timers: {}", e),
+    }
+}
+
+/// Deletes the view-once copies `viewed` belongs to.
+pub async fn viewed(bot: &Bot, db: &Database, chat: i64, viewed: MessageId) -> Result<()> {
+    for msg in db.take_view_once(chat, viewed.0).await? {
+        let _ = bot.delete_message(ChatId(chat), MessageId(msg)).await;
+    }
+
+    Ok(())
+}
+
+/// Deletes everything still pending between two users whose dialog ended.
+pub async fn clear_dialog(bot: &Bot, db: &Database, user_id: i64, partner_id: i64) {
+    match db.take_dialog_self_destructs(user_id, partner_id).await {
+        Ok(copies) => {
+            for (chat, msg) in copies {
+                let _ = bot.delete_message(ChatId(chat), MessageId(msg)).await;
+            }
+        }
+        Err(e) => log::error!("Can't clear self-destructs of {} and {}: {}", user_id, partner_id, e),
+    }
+}
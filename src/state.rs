@@ -1,6 +1,8 @@
-use crate::models::gender::Gender;
+use serde::{ Deserialize, Serialize };
 
-#[derive(Clone, Default, Debug)]
+use crate::models::search_gender::SearchGender;
+
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub enum State {
     #[default]
     Start,
@@ -14,7 +16,7 @@ pub enum State {
     },
     SearchChooseGender,
     SearchChooseChatType {
-        gender: Gender,
+        gender: SearchGender,
     },
     Search,
     Dialog {
@@ -24,4 +26,5 @@ pub enum State {
     SetNickname,
     SetAge,
     SetGender,
+    SetLocation,
 }